@@ -2,7 +2,7 @@ extern crate erl_ext;
 extern crate getopts;
 
 use getopts::Options;
-use erl_ext::{Decoder,Encoder};
+use erl_ext::{Decoder,Encoder,EncoderOptions,AtomEncoding};
 use std::io::Write;
 use std::io;
 use std::env;
@@ -18,19 +18,19 @@ fn main() {
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { panic!("{}", f) }
     };
     if matches.free.len() != 2 {
         let brief = format!("Usage: {} [opts] <in-file or '-'> <out-file or '-'>", args[0]);
         print!("{}", opts.usage(&brief));
         exit(1);
     }
-    let mut in_f: Box<io::Read> = match matches.free[0].as_ref() {
+    let mut in_f: Box<dyn io::Read> = match matches.free[0].as_ref() {
         "-" => Box::new(io::stdin()),
         other =>
             Box::new(fs::File::open(other).unwrap())
     };
-    let mut out_f: Box<io::Write> = match matches.free[1].as_ref() {
+    let mut out_f: Box<dyn io::Write> = match matches.free[1].as_ref() {
         "-" => Box::new(io::stdout()),
         other =>
             Box::new(fs::File::create(other).unwrap())
@@ -54,25 +54,26 @@ fn main() {
         }
         let term = decoder.decode_term().unwrap();
         // print it to stderr
-        (write!(&mut io::stderr(), "{:?}\n", term)).unwrap();
+        (writeln!(&mut io::stderr(), "{:?}", term)).unwrap();
         // and encode it
-        let mut encoder = Encoder::new(&mut wrtr,
-                                       matches.opt_present("u"),
-                                       matches.opt_present("s"),
-                                       matches.opt_present("f"));
+        let options = EncoderOptions::new()
+            .atom_encoding(if matches.opt_present("u") {AtomEncoding::Utf8} else {AtomEncoding::Latin1})
+            .small_atoms(matches.opt_present("s"))
+            .fair_new_fun(matches.opt_present("f"));
+        let mut encoder = Encoder::new(&mut wrtr, options);
         encoder.write_prelude().unwrap();
         encoder.encode_term(term).unwrap();
         encoder.flush().unwrap();
     }
     // write encoded result to out_f
-    out_f.write(wrtr.get_ref()).unwrap();
+    out_f.write_all(wrtr.get_ref()).unwrap();
 
     // compare original and encoded
     // (write!(&mut io::stderr(), "Before {:?}\n", rdr.get_ref())).unwrap();
     // (write!(&mut io::stderr(), "After  {:?}\n", wrtr.get_ref())).unwrap();
 
     if wrtr.get_ref() != rdr.get_ref() {
-        (write!(&mut io::stderr(), "Before and After isn't equal\n")).unwrap();
+        (writeln!(&mut io::stderr(), "Before and After isn't equal")).unwrap();
         exit(1);
     }
 }
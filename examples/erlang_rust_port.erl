@@ -7,8 +7,8 @@
 %% from port until it can be decoded by binary_to_term, and use binary
 %% accumulator for that (see 'recv/2').
 %% Alternatively, we can use '{packet, N}' option, and in Rust side first
-%% encode terms to temporary MemWriter buffer, calculate and write it's size,
-%% and then copy this buffer to stdout.
+%% calculate encoded term size, write it and then encode term to stdout
+%% (see json_port.erl).
 -mode(compile).
 
 main([]) ->
@@ -4,9 +4,9 @@ extern crate erl_ext;
 extern crate getopts;
 
 use getopts::Options;
-use erl_ext::{Decoder,Encoder,Error};
+use erl_ext::{Eterm, EncoderOptions, AtomEncoding};
+use erl_ext::port::{HandlerResult, Packet, PortServer};
 
-use std::io;
 use std::env;
 
 
@@ -19,32 +19,19 @@ fn main() {
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+        Err(f) => { panic!("{}", f) }
     };
 
-    let mut in_f = io::stdin();
-    let mut out_f = io::stdout();
-    // let mut out_writer = std::io::BufferedWriter::with_capacity(20480,
-    //                                                             out_f.unwrap());
-    let decoder = Decoder::new(&mut in_f);
-    let encoder = Encoder::new(&mut out_f,
-                               matches.opt_present("u"),
-                               matches.opt_present("s"),
-                               matches.opt_present("f"));
-    match read_write_loop(decoder, encoder) {
-        Err(Error::ByteorderUnexpectedEOF) => (), // port was closed
-        Err(ref err) =>
-            panic!("Error: {}", err),
-        Ok(()) => ()            // unreachable in this example
-    };
-}
-
-fn read_write_loop<R: io::Read>(mut decoder: Decoder<R>, mut encoder: Encoder) -> Result<(), Error> {
-    loop {
-        assert!(true == try!(decoder.read_prelude()));
-        let term = try!(decoder.decode_term());
-        try!(encoder.write_prelude());
-        try!(encoder.encode_term(term));
-        try!(encoder.flush());
+    // erlang_rust_port.erl doesn't use {packet, N}, so terms are written
+    // right to stdout without calculating their size first
+    let echo = |term: Eterm| -> HandlerResult { Ok(term) };
+    let options = EncoderOptions::new()
+        .atom_encoding(if matches.opt_present("u") {AtomEncoding::Utf8} else {AtomEncoding::Latin1})
+        .small_atoms(matches.opt_present("s"))
+        .fair_new_fun(matches.opt_present("f"));
+    let mut server = PortServer::new(echo, Packet::Stream, options);
+    // returns Ok when port was closed
+    if let Err(err) = server.run() {
+        panic!("Error: {}", err);
     }
 }
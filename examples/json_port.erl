@@ -2,9 +2,8 @@
 %% See json_port.rs
 %%
 %% In this example we use `{packet, 2}`, so, unlike in erlang_rust_port, whole
-%% response packet received at once. But this leads to more complex and less
-%% performant Rust part (since we need to serialize to temporary in-memory
-%% buffer to calculate packet's size).
+%% response packet received at once. The price is that Rust part needs to
+%% calculate encoded term size before writing it (see `erl_ext::encoded_size`).
 -mode(compile).
 
 main([]) ->
@@ -14,7 +14,7 @@ fn main() {
         println!("Usage: parser <filename or '-'>");
         exit(1);
     }
-    let mut f: Box<io::Read> = match args.nth(1).unwrap().as_ref() {
+    let mut f: Box<dyn io::Read> = match args.nth(1).unwrap().as_ref() {
         "-" => Box::new(io::stdin()),
         other =>
             Box::new(fs::File::open(other).unwrap()),
@@ -0,0 +1,215 @@
+// Atoms.
+//
+// Atom text is shared by `Arc`, so clones are cheap. Decoder given an
+// `AtomTable` stores text of each atom once per table, so decoding the same
+// atom again doesn't allocate. Like the VM's atom table, `AtomTable` may be
+// limited in size; decoder fails with `Error::AtomTableFull` when limit is
+// reached. Atoms, made by `Atom::from`, aren't interned.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::fmt;
+
+use super::Error;
+
+
+// Same as the VM's default (`+t` flag)
+pub const DEFAULT_ATOM_LIMIT: usize = 1_048_576;
+
+#[derive(Clone)]
+pub struct Atom(Arc<str>);
+
+impl Atom {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq for Atom {
+    fn eq(&self, other: &Atom) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0 == other.0
+    }
+}
+
+impl Eq for Atom {}
+
+impl PartialOrd for Atom {
+    fn partial_cmp(&self, other: &Atom) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Atom {
+    fn cmp(&self, other: &Atom) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl Hash for Atom {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl Deref for Atom {
+    type Target = str;
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Atom {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Atom {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl PartialEq<str> for Atom {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl<'a> PartialEq<&'a str> for Atom {
+    fn eq(&self, other: &&'a str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+impl fmt::Debug for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Atom {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self.as_str(), f)
+    }
+}
+
+// Not interned, but compares equal to interned atom of the same text
+impl<'a> From<&'a str> for Atom {
+    fn from(name: &'a str) -> Atom {
+        Atom(Arc::from(name))
+    }
+}
+
+impl From<String> for Atom {
+    fn from(name: String) -> Atom {
+        Atom::from(name.as_str())
+    }
+}
+
+impl From<Atom> for String {
+    fn from(atom: Atom) -> String {
+        String::from(atom.as_str())
+    }
+}
+
+
+struct Table {
+    atoms: HashSet<Arc<str>>,
+    limit: Option<usize>,
+}
+
+// Shared (cloned tables are the same table) set of atoms
+#[derive(Clone)]
+pub struct AtomTable(Arc<Mutex<Table>>);
+
+impl AtomTable {
+    // Unlimited table
+    pub fn new() -> AtomTable {
+        AtomTable(Arc::new(Mutex::new(Table{atoms: HashSet::new(), limit: None})))
+    }
+
+    pub fn with_limit(limit: usize) -> AtomTable {
+        let table = AtomTable::new();
+        table.0.lock().unwrap().limit = Some(limit);
+        table
+    }
+
+    // The only place, where limit is checked
+    pub fn intern(&self, name: &str) -> Result<Atom, Error> {
+        let mut table = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(atom) = table.atoms.get(name) {
+            return Ok(Atom(atom.clone()));
+        }
+        if let Some(limit) = table.limit {
+            if table.atoms.len() >= limit {
+                return Err(Error::AtomTableFull(limit));
+            }
+        }
+        let atom: Arc<str> = Arc::from(name);
+        table.atoms.insert(atom.clone());
+        Ok(Atom(atom))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).atoms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).limit
+    }
+}
+
+impl Default for AtomTable {
+    fn default() -> AtomTable {
+        AtomTable::new()
+    }
+}
+
+impl PartialEq for AtomTable {
+    fn eq(&self, other: &AtomTable) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for AtomTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "AtomTable({} atoms, limit {:?})", self.len(), self.limit())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::Error;
+    use super::{Atom, AtomTable};
+
+    #[test]
+    fn atom_intern() {
+        let table = AtomTable::with_limit(2);
+        let ok = table.intern("ok").unwrap();
+        assert!(ok == table.intern("ok").unwrap());
+        assert_eq!(1, table.len());
+        assert_eq!(ok, Atom::from("ok"));
+        assert_eq!(ok, "ok");
+        table.intern("error").unwrap();
+        match table.intern("undefined") {
+            Err(Error::AtomTableFull(2)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        // existing atoms are still found
+        assert_eq!("error", table.intern("error").unwrap().as_str());
+        // atoms, made outside of the table, don't count
+        assert_eq!("undefined", Atom::from("undefined").as_str());
+        assert_eq!(2, table.len());
+        assert_eq!("\"ok\"", format!("{:?}", ok));
+    }
+}
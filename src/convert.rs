@@ -0,0 +1,528 @@
+// Conversions between terms and Rust types:
+//
+//     Rust                          | Erlang
+//     ------------------------------+------------------------------
+//     bool                          | true, false
+//     i8 .. u128, isize, usize      | integer()
+//     f64                           | float()
+//     &str, String                  | binary() (UTF-8)
+//     Vec<T>                        | [T]
+//     (A, B, ..), up to 12 elements | {A, B, ..}
+//     HashMap<K, V>, BTreeMap<K, V> | #{K => V}
+//     Option<T>                     | T | undefined
+//     Result<T, E>                  | {ok, T} | {error, E}
+//     Atom, Pid, Port, Reference    | atom(), pid(), port(), reference()
+//
+// `TryFrom<Eterm>` does the reverse. `String` is also accepted from string
+// (list of characters) terms, and `Vec<T>` from strings as lists of bytes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::convert::{Infallible, TryFrom};
+use std::hash::Hash;
+use std::{error, fmt};
+
+use num::bigint::BigInt;
+
+use super::{Eterm, Atom, Pid, Port, Reference};
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConvertError {
+    WrongType(&'static str, &'static str), // (expected, found) kinds of term
+    OutOfRange(&'static str, String),      // number doesn't fit into Rust type (type, value)
+    WrongArity(usize, usize),              // tuple of other size (expected, found)
+    BadUtf8(usize),                        // binary isn't UTF-8 (valid up to)
+}
+
+impl error::Error for ConvertError {
+    fn description(&self) -> &str {
+        match *self {
+            ConvertError::WrongType(..) => "Term of other type expected",
+            ConvertError::OutOfRange(..) => "Number doesn't fit into type",
+            ConvertError::WrongArity(..) => "Tuple of other size expected",
+            ConvertError::BadUtf8(_) => "Binary isn't valid UTF-8",
+        }
+    }
+}
+impl fmt::Display for ConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConvertError::WrongType(expected, found) => write!(f, "Expected {}, found {}", expected, found),
+            ConvertError::OutOfRange(ty, ref val) => write!(f, "Number {} doesn't fit into {}", val, ty),
+            ConvertError::WrongArity(expected, found) => write!(f, "Expected tuple of {} elements, found {}", expected, found),
+            ConvertError::BadUtf8(pos) => write!(f, "Binary isn't valid UTF-8 after {} bytes", pos),
+        }
+    }
+}
+
+// `Eterm` elements, converted by blanket `TryFrom`
+impl From<Infallible> for ConvertError {
+    fn from(err: Infallible) -> ConvertError {
+        match err {}
+    }
+}
+
+// Kind of term for error messages
+fn kind(term: &Eterm) -> &'static str {
+    match *term {
+        Eterm::SmallInteger(_) | Eterm::Integer(_) | Eterm::BigNum(_) => "integer",
+        Eterm::Float(_) => "float",
+        Eterm::Atom(_) => "atom",
+        Eterm::Reference(_) => "reference",
+        Eterm::Port(_) => "port",
+        Eterm::Pid(_) => "pid",
+        Eterm::Tuple(_) => "tuple",
+        Eterm::Map(_) => "map",
+        Eterm::Nil | Eterm::List(_) | Eterm::String(_) => "list",
+        Eterm::Binary(_) => "binary",
+        Eterm::BitBinary(_) => "bitstring",
+        Eterm::Fun(_) | Eterm::NewFun(_) | Eterm::Export(_) => "fun",
+    }
+}
+
+fn wrong_type(expected: &'static str, term: &Eterm) -> ConvertError {
+    ConvertError::WrongType(expected, kind(term))
+}
+
+fn convert<T>(term: Eterm) -> Result<T, ConvertError>
+    where T: TryFrom<Eterm>, T::Error: Into<ConvertError> {
+    T::try_from(term).map_err(Into::into)
+}
+
+fn atom(name: &str) -> Eterm {
+    Eterm::Atom(Atom::from(name))
+}
+
+// Elements of a proper list, with nested tails flattened
+fn list_elems(term: Eterm) -> Result<Vec<Eterm>, ConvertError> {
+    let mut elems = Vec::new();
+    let mut cur = term;
+    loop {
+        match cur {
+            Eterm::Nil => return Ok(elems),
+            Eterm::String(bytes) => {
+                elems.extend(bytes.into_iter().map(Eterm::SmallInteger));
+                return Ok(elems);
+            },
+            Eterm::List(mut list) => match list.pop() {
+                Some(tail) => {
+                    elems.append(&mut list);
+                    cur = tail;
+                },
+                None => return Ok(elems),
+            },
+            ref other if elems.is_empty() =>
+                return Err(wrong_type("list", other)),
+            _ =>
+                return Err(ConvertError::WrongType("proper list", "improper list")),
+        }
+    }
+}
+
+
+impl From<bool> for Eterm {
+    fn from(val: bool) -> Eterm {
+        atom(if val { "true" } else { "false" })
+    }
+}
+
+impl TryFrom<Eterm> for bool {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<bool, ConvertError> {
+        match term {
+            Eterm::Atom(ref a) if *a == "true" => Ok(true),
+            Eterm::Atom(ref a) if *a == "false" => Ok(false),
+            ref other => Err(wrong_type("boolean", other)),
+        }
+    }
+}
+
+macro_rules! int_convert {
+    ($($t:ident),*) => {
+        $(impl From<$t> for Eterm {
+            fn from(num: $t) -> Eterm {
+                Eterm::from_i128(num as i128)
+            }
+        }
+
+        impl TryFrom<Eterm> for $t {
+            type Error = ConvertError;
+
+            fn try_from(term: Eterm) -> Result<$t, ConvertError> {
+                let res = match term {
+                    Eterm::SmallInteger(num) => $t::try_from(num).ok(),
+                    Eterm::Integer(num) => $t::try_from(num).ok(),
+                    Eterm::BigNum(ref num) => $t::try_from(num).ok(),
+                    ref other => return Err(wrong_type("integer", other)),
+                };
+                res.ok_or_else(|| ConvertError::OutOfRange(stringify!($t), term.to_string()))
+            }
+        })*
+    }
+}
+int_convert!(i8, i16, i32, i64, i128, isize, u8, u16, u32, u64, usize);
+
+impl From<u128> for Eterm {
+    fn from(num: u128) -> Eterm {
+        Eterm::from_u128(num)
+    }
+}
+
+impl TryFrom<Eterm> for u128 {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<u128, ConvertError> {
+        let res = match term {
+            Eterm::SmallInteger(num) => Some(num as u128),
+            Eterm::Integer(num) => u128::try_from(num).ok(),
+            Eterm::BigNum(ref num) => u128::try_from(num).ok(),
+            ref other => return Err(wrong_type("integer", other)),
+        };
+        res.ok_or_else(|| ConvertError::OutOfRange("u128", term.to_string()))
+    }
+}
+
+impl From<BigInt> for Eterm {
+    fn from(num: BigInt) -> Eterm {
+        Eterm::BigNum(num)
+    }
+}
+
+impl TryFrom<Eterm> for BigInt {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<BigInt, ConvertError> {
+        match term {
+            Eterm::SmallInteger(num) => Ok(BigInt::from(num)),
+            Eterm::Integer(num) => Ok(BigInt::from(num)),
+            Eterm::BigNum(num) => Ok(num),
+            ref other => Err(wrong_type("integer", other)),
+        }
+    }
+}
+
+impl From<f64> for Eterm {
+    fn from(num: f64) -> Eterm {
+        Eterm::Float(num)
+    }
+}
+
+// Integers aren't converted to floats, like Erlang's `is_float/1`
+impl TryFrom<Eterm> for f64 {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<f64, ConvertError> {
+        match term {
+            Eterm::Float(num) => Ok(num),
+            ref other => Err(wrong_type("float", other)),
+        }
+    }
+}
+
+impl<'a> From<&'a str> for Eterm {
+    fn from(s: &'a str) -> Eterm {
+        Eterm::Binary(s.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for Eterm {
+    fn from(s: String) -> Eterm {
+        Eterm::Binary(s.into_bytes())
+    }
+}
+
+// From UTF-8 binary or list of characters
+impl TryFrom<Eterm> for String {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<String, ConvertError> {
+        match term {
+            Eterm::Binary(bytes) =>
+                String::from_utf8(bytes).map_err(|e| ConvertError::BadUtf8(e.utf8_error().valid_up_to())),
+            Eterm::String(bytes) =>
+                Ok(bytes.into_iter().map(char::from).collect()),
+            list @ Eterm::List(_) | list @ Eterm::Nil =>
+                list_elems(list)?.into_iter().map(|elem| {
+                    let code = u32::try_from(elem)?;
+                    char::from_u32(code).ok_or_else(|| ConvertError::OutOfRange("char", code.to_string()))
+                }).collect(),
+            ref other => Err(wrong_type("binary or string", other)),
+        }
+    }
+}
+
+impl From<Atom> for Eterm {
+    fn from(a: Atom) -> Eterm {
+        Eterm::Atom(a)
+    }
+}
+
+impl TryFrom<Eterm> for Atom {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<Atom, ConvertError> {
+        match term {
+            Eterm::Atom(a) => Ok(a),
+            ref other => Err(wrong_type("atom", other)),
+        }
+    }
+}
+
+macro_rules! struct_convert {
+    ($($t:ident => $name:expr),*) => {
+        $(impl From<$t> for Eterm {
+            fn from(val: $t) -> Eterm {
+                Eterm::$t(val)
+            }
+        }
+
+        impl TryFrom<Eterm> for $t {
+            type Error = ConvertError;
+
+            fn try_from(term: Eterm) -> Result<$t, ConvertError> {
+                match term {
+                    Eterm::$t(val) => Ok(val),
+                    ref other => Err(wrong_type($name, other)),
+                }
+            }
+        })*
+    }
+}
+struct_convert!(Pid => "pid", Port => "port", Reference => "reference");
+
+impl<T: Into<Eterm>> From<Vec<T>> for Eterm {
+    fn from(vec: Vec<T>) -> Eterm {
+        if vec.is_empty() {
+            return Eterm::Nil;
+        }
+        let mut elems: Vec<Eterm> = vec.into_iter().map(Into::into).collect();
+        elems.push(Eterm::Nil);
+        Eterm::List(elems)
+    }
+}
+
+impl<T> TryFrom<Eterm> for Vec<T>
+    where T: TryFrom<Eterm>, T::Error: Into<ConvertError> {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<Vec<T>, ConvertError> {
+        list_elems(term)?.into_iter().map(convert).collect()
+    }
+}
+
+// `undefined` is the usual "no value" of Erlang APIs
+impl<T: Into<Eterm>> From<Option<T>> for Eterm {
+    fn from(opt: Option<T>) -> Eterm {
+        match opt {
+            Some(val) => val.into(),
+            None => atom("undefined"),
+        }
+    }
+}
+
+// Generic `Option<T>` would conflict with `Option<Eterm>: From<Eterm>`, so
+// it's implemented for each convertible type
+macro_rules! option_convert {
+    ($({$($g:ident),*} $t:ty;)*) => {
+        $(impl<$($g),*> TryFrom<Eterm> for Option<$t>
+            where $t: TryFrom<Eterm>, <$t as TryFrom<Eterm>>::Error: Into<ConvertError> {
+            type Error = ConvertError;
+
+            fn try_from(term: Eterm) -> Result<Option<$t>, ConvertError> {
+                match term {
+                    Eterm::Atom(ref a) if *a == "undefined" => Ok(None),
+                    other => convert(other).map(Some),
+                }
+            }
+        })*
+    }
+}
+option_convert! {
+    {} bool; {} i8; {} i16; {} i32; {} i64; {} i128; {} isize;
+    {} u8; {} u16; {} u32; {} u64; {} u128; {} usize; {} BigInt; {} f64;
+    {} String; {} Atom; {} Pid; {} Port; {} Reference;
+    {T} Vec<T>; {K, V} HashMap<K, V>; {K, V} BTreeMap<K, V>; {T, E} Result<T, E>;
+}
+
+impl<T: Into<Eterm>, E: Into<Eterm>> From<Result<T, E>> for Eterm {
+    fn from(res: Result<T, E>) -> Eterm {
+        match res {
+            Ok(val) => Eterm::Tuple(vec!(atom("ok"), val.into())),
+            Err(err) => Eterm::Tuple(vec!(atom("error"), err.into())),
+        }
+    }
+}
+
+impl<T, E> TryFrom<Eterm> for Result<T, E>
+    where T: TryFrom<Eterm>, T::Error: Into<ConvertError>,
+          E: TryFrom<Eterm>, E::Error: Into<ConvertError> {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<Result<T, E>, ConvertError> {
+        match term {
+            Eterm::Tuple(mut elems) if elems.len() == 2 => {
+                let val = elems.pop().unwrap();
+                match elems.pop().unwrap() {
+                    Eterm::Atom(ref a) if *a == "ok" => convert(val).map(Ok),
+                    Eterm::Atom(ref a) if *a == "error" => convert(val).map(Err),
+                    ref other => Err(wrong_type("ok or error", other)),
+                }
+            },
+            ref other => Err(wrong_type("{ok, _} or {error, _}", other)),
+        }
+    }
+}
+
+impl<K: Into<Eterm>, V: Into<Eterm>, S> From<HashMap<K, V, S>> for Eterm {
+    fn from(map: HashMap<K, V, S>) -> Eterm {
+        Eterm::Map(map.into())
+    }
+}
+
+impl<K, V> TryFrom<Eterm> for HashMap<K, V>
+    where K: TryFrom<Eterm> + Eq + Hash, K::Error: Into<ConvertError>,
+          V: TryFrom<Eterm>, V::Error: Into<ConvertError> {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<HashMap<K, V>, ConvertError> {
+        match term {
+            Eterm::Map(pairs) =>
+                pairs.into_iter().map(|(k, v)| Ok((convert(k)?, convert(v)?))).collect(),
+            ref other => Err(wrong_type("map", other)),
+        }
+    }
+}
+
+impl<K: Into<Eterm>, V: Into<Eterm>> From<BTreeMap<K, V>> for Eterm {
+    fn from(map: BTreeMap<K, V>) -> Eterm {
+        Eterm::Map(map.into())
+    }
+}
+
+impl<K, V> TryFrom<Eterm> for BTreeMap<K, V>
+    where K: TryFrom<Eterm> + Ord, K::Error: Into<ConvertError>,
+          V: TryFrom<Eterm>, V::Error: Into<ConvertError> {
+    type Error = ConvertError;
+
+    fn try_from(term: Eterm) -> Result<BTreeMap<K, V>, ConvertError> {
+        match term {
+            Eterm::Map(pairs) =>
+                pairs.into_iter().map(|(k, v)| Ok((convert(k)?, convert(v)?))).collect(),
+            ref other => Err(wrong_type("map", other)),
+        }
+    }
+}
+
+macro_rules! tuple_convert {
+    ($($len:expr => ($($t:ident),+);)*) => {
+        $(impl<$($t: Into<Eterm>),+> From<($($t,)+)> for Eterm {
+            #[allow(non_snake_case)]
+            fn from(($($t,)+): ($($t,)+)) -> Eterm {
+                Eterm::Tuple(vec!($($t.into()),+))
+            }
+        }
+
+        impl<$($t),+> TryFrom<Eterm> for ($($t,)+)
+            where $($t: TryFrom<Eterm>, $t::Error: Into<ConvertError>),+ {
+            type Error = ConvertError;
+
+            fn try_from(term: Eterm) -> Result<($($t,)+), ConvertError> {
+                match term {
+                    Eterm::Tuple(elems) if elems.len() == $len => {
+                        let mut elems = elems.into_iter();
+                        Ok(($(convert::<$t>(elems.next().unwrap())?,)+))
+                    },
+                    Eterm::Tuple(elems) => Err(ConvertError::WrongArity($len, elems.len())),
+                    ref other => Err(wrong_type("tuple", other)),
+                }
+            }
+        }
+
+        option_convert! { {$($t),+} ($($t,)+); })*
+    }
+}
+tuple_convert! {
+    1 => (A);
+    2 => (A, B);
+    3 => (A, B, C);
+    4 => (A, B, C, D);
+    5 => (A, B, C, D, E);
+    6 => (A, B, C, D, E, F);
+    7 => (A, B, C, D, E, F, G);
+    8 => (A, B, C, D, E, F, G, H);
+    9 => (A, B, C, D, E, F, G, H, I);
+    10 => (A, B, C, D, E, F, G, H, I, J);
+    11 => (A, B, C, D, E, F, G, H, I, J, K);
+    12 => (A, B, C, D, E, F, G, H, I, J, K, L);
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use std::convert::TryFrom;
+    use num::bigint::BigInt;
+    use super::super::{Atom, Eterm};
+    use super::ConvertError;
+
+    #[test]
+    fn convert_into_term() {
+        assert_eq!("true", Eterm::from(true).to_string());
+        assert_eq!(Eterm::SmallInteger(7), Eterm::from(7u64));
+        assert_eq!(Eterm::Integer(-7), Eterm::from(-7i8));
+        assert_eq!("340282366920938463463374607431768211455", Eterm::from(u128::MAX).to_string());
+        assert_eq!(Eterm::Binary(b"abc".to_vec()), Eterm::from("abc"));
+        assert_eq!("[1,2,3]", Eterm::from(vec!(1, 2, 3)).to_string());
+        assert_eq!(Eterm::Nil, Eterm::from(Vec::<i32>::new()));
+        assert_eq!("{ok,<<\"a\">>,1.5,[]}", Eterm::from((Atom::from("ok"), "a", 1.5, Vec::<u8>::new())).to_string());
+        assert_eq!("undefined", Eterm::from(None::<i32>).to_string());
+        assert_eq!("{error,<<\"bad\">>}", Eterm::from(Err::<i32, _>("bad")).to_string());
+        let mut map = BTreeMap::new();
+        map.insert("b", vec!(Some(1), None));
+        map.insert("a", vec!());
+        assert_eq!("#{<<\"a\">> => [],<<\"b\">> => [1,undefined]}", Eterm::from(map).to_string());
+        let mut hmap = HashMap::new();
+        hmap.insert(1, 2);
+        assert_eq!(Eterm::Map(vec!((Eterm::SmallInteger(1), Eterm::SmallInteger(2))).into()), Eterm::from(hmap));
+    }
+
+    #[test]
+    fn convert_from_term() {
+        let term = |s: &str| s.parse::<Eterm>().unwrap();
+        assert_eq!(Ok(false), bool::try_from(term("false")));
+        assert_eq!(Ok(-5i64), i64::try_from(term("-5")));
+        assert_eq!(Ok(1u64 << 63), u64::try_from(term("9223372036854775808")));
+        assert_eq!(Ok(BigInt::from(255)), BigInt::try_from(term("255")));
+        assert_eq!(Ok(2.5), f64::try_from(term("2.5")));
+        assert_eq!(Ok("жук".to_string()), String::try_from(term("<<\"жук\"/utf8>>")));
+        assert_eq!(Ok("жук".to_string()), String::try_from(term("\"жук\"")));
+        assert_eq!(Ok("abc".to_string()), String::try_from(term("\"abc\"")));
+        assert_eq!(Ok(vec!(97u8, 98)), Vec::<u8>::try_from(term("\"ab\"")));
+        assert_eq!(Ok(vec!(1, 2, 3)), Vec::<i32>::try_from(term("[1, 2 | [3]]")));
+        assert_eq!(Ok((Atom::from("ok"), 1u8, vec!(Some(true), None))),
+                   <(Atom, u8, Vec<Option<bool>>)>::try_from(term("{ok, 1, [true, undefined]}")));
+        assert_eq!(Ok(Err::<i32, String>("x".to_string())),
+                   Result::<i32, String>::try_from(term("{error, <<\"x\">>}")));
+        let map = HashMap::<String, i32>::try_from(term("#{<<\"a\">> => 1, <<\"b\">> => 2}")).unwrap();
+        assert_eq!((2, Some(&2)), (map.len(), map.get("b")));
+        let tree = BTreeMap::<i32, Vec<i32>>::try_from(term("#{2 => [], 1 => [1]}")).unwrap();
+        assert_eq!(vec!((&1, &vec!(1)), (&2, &vec!())), tree.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn convert_errors() {
+        let term = |s: &str| s.parse::<Eterm>().unwrap();
+        assert_eq!(Err(ConvertError::WrongType("integer", "atom")), i32::try_from(term("ok")));
+        assert_eq!(Err(ConvertError::OutOfRange("u8", "256".to_string())), u8::try_from(term("256")));
+        assert_eq!(Err(ConvertError::OutOfRange("u32", "-1".to_string())), u32::try_from(term("-1")));
+        assert_eq!(Err(ConvertError::WrongType("float", "integer")), f64::try_from(term("1")));
+        assert_eq!(Err(ConvertError::WrongArity(2, 3)), <(i32, i32)>::try_from(term("{1, 2, 3}")));
+        assert_eq!(Err(ConvertError::WrongType("proper list", "improper list")),
+                   Vec::<i32>::try_from(term("[1 | 2]")));
+        assert_eq!(Err(ConvertError::BadUtf8(1)), String::try_from(term("<<97, 255>>")));
+        assert_eq!(Err(ConvertError::WrongType("boolean", "atom")), Vec::<bool>::try_from(term("[true, maybe_]")));
+        assert_eq!("Expected tuple, found list",
+                   <(i32,)>::try_from(term("[]")).unwrap_err().to_string());
+    }
+}
@@ -0,0 +1,316 @@
+// Erlang syntax of terms, same as `io_lib:format("~p", [Term])` prints them,
+// but without line breaks.
+//
+// Pids, ports and references are printed as local ones (node index 0), since
+// VM's node table indexes aren't known outside of it: `<0.12.0>`,
+// `#Port<0.5>`, `#Ref<0.1.2.3>`.
+
+use std::fmt;
+
+use super::{Eterm, Pid, Port, Reference, BitBinary, byte_value};
+use super::order;
+
+
+pub(crate) const RESERVED_WORDS: &[&str] = &[
+    "after", "and", "andalso", "band", "begin", "bnot", "bor", "bsl", "bsr",
+    "bxor", "case", "catch", "cond", "div", "else", "end", "fun", "if", "let",
+    "maybe", "not", "of", "or", "orelse", "receive", "rem", "try", "when", "xor"];
+
+pub(crate) fn is_lower(c: char) -> bool {
+    c.is_ascii_lowercase() || ('\u{df}'..='\u{ff}').contains(&c) && c != '\u{f7}'
+}
+
+pub(crate) fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '@' || is_lower(c)
+        || ('\u{c0}'..='\u{de}').contains(&c) && c != '\u{d7}'
+}
+
+fn atom_needs_quotes(name: &str) -> bool {
+    match name.chars().next() {
+        Some(first) if is_lower(first) =>
+            !name.chars().all(is_name_char) || RESERVED_WORDS.contains(&name),
+        _ => true,
+    }
+}
+
+// Characters, which `io_lib:printable_latin1_list/1` accepts
+fn is_printable(byte: u8) -> bool {
+    matches!(byte, 32..=126 | 160..=255 | 8..=13 | 27)
+}
+
+fn write_char(f: &mut fmt::Formatter, c: char, quote: char) -> fmt::Result {
+    match c {
+        '\n' => f.write_str("\\n"),
+        '\r' => f.write_str("\\r"),
+        '\t' => f.write_str("\\t"),
+        '\u{b}' => f.write_str("\\v"),
+        '\u{8}' => f.write_str("\\b"),
+        '\u{c}' => f.write_str("\\f"),
+        '\u{1b}' => f.write_str("\\e"),
+        '\u{7f}' => f.write_str("\\d"),
+        '\\' => f.write_str("\\\\"),
+        _ if c == quote => write!(f, "\\{}", quote),
+        _ if (c as u32) < 32 => write!(f, "\\{:03o}", c as u32),
+        _ => write!(f, "{}", c),
+    }
+}
+
+pub(crate) fn write_atom(f: &mut fmt::Formatter, name: &str) -> fmt::Result {
+    if !atom_needs_quotes(name) {
+        return f.write_str(name);
+    }
+    f.write_str("'")?;
+    for c in name.chars() {
+        write_char(f, c, '\'')?;
+    }
+    f.write_str("'")
+}
+
+fn write_string(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    f.write_str("\"")?;
+    for byte in bytes.iter() {
+        write_char(f, *byte as char, '"')?;
+    }
+    f.write_str("\"")
+}
+
+// Shortest digits, which read back as the same float, placed like
+// `io_lib_format:fwrite_g/1` does: fixed notation unless exponent is shorter
+pub(crate) fn write_float(f: &mut fmt::Formatter, num: f64) -> fmt::Result {
+    if num.is_sign_negative() {
+        f.write_str("-")?;
+    }
+    let num = num.abs();
+    if num == 0.0 {
+        return f.write_str("0.0");
+    }
+    // "d.ddde[-]x" of the shortest representation
+    let sci = format!("{:e}", num);
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap());
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    // num = 0.digits * 10^place
+    let place = exp[1..].parse::<i32>().unwrap() + 1;
+    let len = digits.len() as i32;
+    if 0 < place && place < len {
+        let (int, frac) = digits.split_at(place as usize);
+        return write!(f, "{}.{}", int, frac);
+    }
+    if place == 0 {
+        return write!(f, "0.{}", digits);
+    }
+    let exp = (place - 1).to_string();
+    let exp_cost = exp.len() as i32 + 1 + if len == 1 { 2 } else { 1 };
+    if place < 0 && 2 - place <= exp_cost {
+        write!(f, "0.{}{}", "0".repeat(-place as usize), digits)
+    } else if place >= len && place - len + 2 <= exp_cost {
+        write!(f, "{}{}.0", digits, "0".repeat((place - len) as usize))
+    } else if len == 1 {
+        write!(f, "{}.0e{}", digits, exp)
+    } else {
+        write!(f, "{}.{}e{}", &digits[..1], &digits[1..], exp)
+    }
+}
+
+fn write_seq(f: &mut fmt::Formatter, elems: &[Eterm]) -> fmt::Result {
+    for (i, elem) in elems.iter().enumerate() {
+        if i > 0 {
+            f.write_str(",")?;
+        }
+        write!(f, "{}", elem)?;
+    }
+    Ok(())
+}
+
+fn write_list(f: &mut fmt::Formatter, list: &Eterm) -> fmt::Result {
+    let (heads, tail) = order::list_parts(list);
+    if heads.is_empty() {
+        // list is its tail
+        return write!(f, "{}", tail);
+    }
+    if *tail == Eterm::Nil {
+        let bytes: Option<Vec<u8>> = heads.iter().map(|head| byte_value(head)).collect();
+        if let Some(bytes) = bytes.filter(|bytes| bytes.iter().all(|b| is_printable(*b))) {
+            return write_string(f, &bytes);
+        }
+    }
+    f.write_str("[")?;
+    for (i, head) in heads.iter().enumerate() {
+        if i > 0 {
+            f.write_str(",")?;
+        }
+        write!(f, "{}", head)?;
+    }
+    if *tail != Eterm::Nil {
+        write!(f, "|{}", tail)?;
+    }
+    f.write_str("]")
+}
+
+fn write_binary(f: &mut fmt::Formatter, data: &[u8]) -> fmt::Result {
+    if !data.is_empty() && data.iter().all(|b| is_printable(*b)) {
+        f.write_str("<<")?;
+        write_string(f, data)?;
+        return f.write_str(">>");
+    }
+    f.write_str("<<")?;
+    for (i, byte) in data.iter().enumerate() {
+        if i > 0 {
+            f.write_str(",")?;
+        }
+        write!(f, "{}", byte)?;
+    }
+    f.write_str(">>")
+}
+
+impl fmt::Display for BitBinary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (last, full) = match self.data.split_last() {
+            Some(parts) if self.bits < 8 => parts,
+            _ => return write_binary(f, &self.data),
+        };
+        f.write_str("<<")?;
+        for byte in full.iter() {
+            write!(f, "{},", byte)?;
+        }
+        write!(f, "{}:{}>>", last >> (8 - self.bits), self.bits)
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<0.{}.{}>", self.id, self.serial)
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#Port<0.{}>", self.id)
+    }
+}
+
+impl fmt::Display for Reference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("#Ref<0")?;
+        // most significant word first
+        for word in self.id().iter().rev() {
+            write!(f, ".{}", word)?;
+        }
+        f.write_str(">")
+    }
+}
+
+impl fmt::Display for Eterm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Eterm::SmallInteger(num) => write!(f, "{}", num),
+            Eterm::Integer(num) => write!(f, "{}", num),
+            Eterm::BigNum(ref num) => write!(f, "{}", num),
+            Eterm::Float(num) => write_float(f, num),
+            Eterm::Atom(ref atom) => write_atom(f, atom),
+            Eterm::Reference(ref reference) => write!(f, "{}", reference),
+            Eterm::Port(ref port) => write!(f, "{}", port),
+            Eterm::Pid(ref pid) => write!(f, "{}", pid),
+            Eterm::Tuple(ref elems) => {
+                f.write_str("{")?;
+                write_seq(f, elems)?;
+                f.write_str("}")
+            },
+            Eterm::Map(ref pairs) => {
+                f.write_str("#{")?;
+                for (i, (key, val)) in pairs.sorted().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{} => {}", key, val)?;
+                }
+                f.write_str("}")
+            },
+            Eterm::Nil => f.write_str("[]"),
+            Eterm::String(ref bytes) if bytes.iter().all(|b| is_printable(*b)) =>
+                if bytes.is_empty() { f.write_str("[]") } else { write_string(f, bytes) },
+            Eterm::String(ref bytes) => {
+                f.write_str("[")?;
+                for (i, byte) in bytes.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", byte)?;
+                }
+                f.write_str("]")
+            },
+            Eterm::List(_) => write_list(f, self),
+            Eterm::Binary(ref data) => write_binary(f, data),
+            Eterm::BitBinary(ref bit_bin) => write!(f, "{}", bit_bin),
+            Eterm::Fun(ref fun) => {
+                f.write_str("#Fun<")?;
+                write_atom(f, &fun.module)?;
+                write!(f, ".{}.{}>", fun.index, fun.uniq)
+            },
+            Eterm::NewFun(ref fun) => {
+                f.write_str("#Fun<")?;
+                write_atom(f, &fun.module)?;
+                write!(f, ".{}.{}>", fun.old_index, fun.old_uniq)
+            },
+            Eterm::Export(ref export) => {
+                f.write_str("fun ")?;
+                write_atom(f, &export.module)?;
+                f.write_str(":")?;
+                write_atom(f, &export.function)?;
+                write!(f, "/{}", export.arity)
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::{Atom, Eterm, Pid, Reference, BitBinary, Export};
+
+    fn atom(name: &str) -> Eterm {
+        Eterm::Atom(Atom::from(name))
+    }
+
+    #[test]
+    fn display_terms() {
+        let cases = vec!(
+            (Eterm::Tuple(vec!(atom("ok"), Eterm::Binary(b"hi".to_vec()))), "{ok,<<\"hi\">>}"),
+            (Eterm::Map(vec!((atom("b"), Eterm::Nil), (atom("a"), Eterm::SmallInteger(1))).into()),
+             "#{a => 1,b => []}"),
+            (Eterm::Pid(Pid::new("nonode@nohost", 12, 0, 0).unwrap()), "<0.12.0>"),
+            (Eterm::Reference(Reference::new("n@h", &[3, 2, 1], 0).unwrap()), "#Ref<0.1.2.3>"),
+            (Eterm::Export(Export::new("m", "f", 1).unwrap()), "fun m:f/1"),
+            (atom("hello_World@1"), "hello_World@1"),
+            (atom("Hello"), "'Hello'"),
+            (atom("end"), "'end'"),
+            (atom("it's\n"), "'it\\'s\\n'"),
+            (atom(""), "''"),
+            (Eterm::String(b"say \"hi\"\n".to_vec()), "\"say \\\"hi\\\"\\n\""),
+            (Eterm::String(vec!(1, 2)), "[1,2]"),
+            (Eterm::List(vec!(Eterm::Integer(104), Eterm::SmallInteger(105), Eterm::Nil)), "\"hi\""),
+            (Eterm::List(vec!(Eterm::Integer(-1), Eterm::Nil)), "[-1]"),
+            (Eterm::List(vec!(atom("a"), atom("b"))), "[a|b]"),
+            (Eterm::List(vec!(atom("a"), Eterm::List(vec!(atom("b"), Eterm::Nil)))), "[a,b]"),
+            (Eterm::List(vec!(atom("a"))), "a"),
+            (Eterm::List(vec!(Eterm::Nil)), "[]"),
+            (Eterm::Binary(vec!()), "<<>>"),
+            (Eterm::Binary(vec!(0, 255)), "<<0,255>>"),
+            (Eterm::BitBinary(BitBinary{bits: 4, data: vec!(1, 0xF0)}), "<<1,15:4>>"),
+            (Eterm::from_i64(-5_000_000_000), "-5000000000"));
+        for (term, expected) in cases {
+            assert_eq!(expected, format!("{}", term));
+        }
+    }
+
+    #[test]
+    fn display_floats() {
+        let cases = vec!(
+            (1.0, "1.0"), (0.1, "0.1"), (-2.5, "-2.5"), (0.0, "0.0"), (-0.0, "-0.0"),
+            (100.0, "100.0"), (123.456, "123.456"), (0.001, "0.001"), (1.0e-5, "1.0e-5"),
+            (1.0e20, "1.0e20"), (1.5e300, "1.5e300"), (1234567.0, "1234567.0"),
+            (0.30000000000000004, "0.30000000000000004"), (1.0e15, "1.0e15"));
+        for (num, expected) in cases {
+            assert_eq!(expected, format!("{}", Eterm::Float(num)));
+        }
+    }
+}
@@ -6,21 +6,48 @@
 
 extern crate num;
 extern crate byteorder;
+extern crate flate2;
 
 use std::string::String;
 use std::vec::Vec;
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
+use std::str;
+use std::borrow::Borrow;
+use std::collections::HashMap;
 use std::{error, fmt};
-use std::mem::transmute;
+use std::convert::TryFrom;
 
-use num::FromPrimitive;
 use num::bigint;
+use num::traits::ToPrimitive;
 use std::num::ParseFloatError;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-
-
-#[derive(Debug, PartialEq)]
+use flate2::Compression;
+use flate2::bufread::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+
+#[macro_use]
+#[doc(hidden)]
+pub mod macros;
+pub mod port;
+pub mod validate;
+use validate::{check_atom, check_reference_id, check_fun_uniq, check_float,
+               check_creation, check_port_id, check_bit_binary, check_list, check_length};
+mod options;
+mod order;
+mod atom;
+mod display;
+mod parse;
+mod convert;
+pub mod map;
+
+pub use options::{EncoderOptions, DecoderOptions, AtomEncoding, FloatFormat, IdentifierTags, MapOrder};
+pub use atom::{Atom, AtomTable, DEFAULT_ATOM_LIMIT};
+pub use convert::ConvertError;
+pub use map::ErlMap;
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum ErlTermTag {
     // ATOM_CACHE_REF = 82,
     SMALL_INTEGER_EXT = 97,
@@ -48,28 +75,63 @@ pub enum ErlTermTag {
     NEW_FLOAT_EXT = 70,
     ATOM_UTF8_EXT = 118,
     SMALL_ATOM_UTF8_EXT = 119,
+    NEW_PID_EXT = 88,
+    NEW_PORT_EXT = 89,
+    NEWER_REFERENCE_EXT = 90,
+    V4_PORT_EXT = 120,
+    COMPRESSED = 80,
 }
 
-// https://www.reddit.com/r/rust/comments/36pgn9/integer_to_enum_after_removal_of_fromprimitive/
 impl ErlTermTag {
     fn from_u8(t: u8) -> Option<ErlTermTag> {
-        if (t <= 119 && t >= 94) || (t == 77) || (t == 70) {
-            Some(unsafe { transmute(t) })
-        } else {
-            None
-        }
+        use ErlTermTag::*;
+        Some(match t {
+            97 => SMALL_INTEGER_EXT,
+            98 => INTEGER_EXT,
+            99 => FLOAT_EXT,
+            100 => ATOM_EXT,
+            101 => REFERENCE_EXT,
+            102 => PORT_EXT,
+            103 => PID_EXT,
+            104 => SMALL_TUPLE_EXT,
+            105 => LARGE_TUPLE_EXT,
+            116 => MAP_EXT,
+            106 => NIL_EXT,
+            107 => STRING_EXT,
+            108 => LIST_EXT,
+            109 => BINARY_EXT,
+            110 => SMALL_BIG_EXT,
+            111 => LARGE_BIG_EXT,
+            114 => NEW_REFERENCE_EXT,
+            115 => SMALL_ATOM_EXT,
+            117 => FUN_EXT,
+            112 => NEW_FUN_EXT,
+            113 => EXPORT_EXT,
+            77 => BIT_BINARY_EXT,
+            70 => NEW_FLOAT_EXT,
+            118 => ATOM_UTF8_EXT,
+            119 => SMALL_ATOM_UTF8_EXT,
+            88 => NEW_PID_EXT,
+            89 => NEW_PORT_EXT,
+            90 => NEWER_REFERENCE_EXT,
+            120 => V4_PORT_EXT,
+            80 => COMPRESSED,
+            _ => return None
+        })
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// `==` on terms is Erlang's `=:=` and `Ord` is Erlang term order (see `order`
+// module), so terms may be used as keys of `BTreeMap` / `HashMap`.
+#[derive(Debug, Clone)]
 pub enum Eterm {
     SmallInteger(u8),           // small_integer
     Integer(i32),               // integer
     Float(f64),                 // float, new_float
     Atom(Atom),                 // atom, small_atom, atom_utf8, small_atom_utf8
-    Reference(Reference),       // reference, new_reference TODO
-    Port(Port),                 // poort TODO
-    Pid(Pid),                   // pid
+    Reference(Reference),       // reference, new_reference, newer_reference
+    Port(Port),                 // port, new_port, v4_port
+    Pid(Pid),                   // pid, new_pid
     Tuple(Tuple),               // small_tuple, large_tuple
     Map(Map),                   // map
     Nil,                        // nil
@@ -82,29 +144,120 @@ pub enum Eterm {
     Export(Export),             // export TODO
     BitBinary(BitBinary),       // bit_binary; maybe implement .to_bitv() -> Bitv for it? TODO
 }
-pub type Atom = String;
 pub type Tuple = Vec<Eterm>;
-pub type Map = Vec<(Eterm, Eterm)>; // k-v pairs
+pub type Map = ErlMap; // unique keys, see map.rs
 pub type List = Vec<Eterm>;
 
+// Integer constructors pick the same representation decoder produces for
+// OTP-encoded integers: SmallInteger for 0..255, Integer for the rest of i32
+// range and BigNum otherwise.
+impl Eterm {
+    pub fn from_i64(num: i64) -> Eterm {
+        Eterm::from_i128(num as i128)
+    }
+    pub fn from_u64(num: u64) -> Eterm {
+        Eterm::from_i128(num as i128)
+    }
+    pub fn from_i128(num: i128) -> Eterm {
+        if (0..=255).contains(&num) {
+            Eterm::SmallInteger(num as u8)
+        } else if (i32::MIN as i128..=i32::MAX as i128).contains(&num) {
+            Eterm::Integer(num as i32)
+        } else {
+            Eterm::BigNum(bigint::BigInt::from(num))
+        }
+    }
+    pub fn from_u128(num: u128) -> Eterm {
+        if num <= i128::MAX as u128 {
+            Eterm::from_i128(num as i128)
+        } else {
+            Eterm::BigNum(bigint::BigInt::from(num))
+        }
+    }
+
+    // Numeric accessors accept any integer representation; None for other
+    // terms and integers out of range
+    pub fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Eterm::SmallInteger(num) => Some(num as i64),
+            Eterm::Integer(num) => Some(num as i64),
+            Eterm::BigNum(ref num) => num.to_i64(),
+            _ => None,
+        }
+    }
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Eterm::SmallInteger(num) => Some(num as u64),
+            Eterm::Integer(num) => u64::try_from(num).ok(),
+            Eterm::BigNum(ref num) => num.to_u64(),
+            _ => None,
+        }
+    }
+    pub fn as_bigint(&self) -> Option<bigint::BigInt> {
+        match *self {
+            Eterm::SmallInteger(num) => Some(bigint::BigInt::from(num)),
+            Eterm::Integer(num) => Some(bigint::BigInt::from(num)),
+            Eterm::BigNum(ref num) => Some(num.clone()),
+            _ => None,
+        }
+    }
+    // Integers are converted like `float/1` does; None if too large for f64
+    pub fn as_f64(&self) -> Option<f64> {
+        match *self {
+            Eterm::Float(num) => Some(num),
+            Eterm::SmallInteger(num) => Some(num as f64),
+            Eterm::Integer(num) => Some(num as f64),
+            Eterm::BigNum(ref num) => num.to_f64().filter(|num| num.is_finite()),
+            _ => None,
+        }
+    }
+
+    // Same term with integers in the representation `from_i128` picks, in
+    // all nested terms (map keys and fun free variables too)
+    pub fn normalize(self) -> Eterm {
+        match self {
+            Eterm::Integer(num) => Eterm::from_i64(num as i64),
+            Eterm::BigNum(num) => match num.to_i128() {
+                Some(small) => Eterm::from_i128(small),
+                None => Eterm::BigNum(num),
+            },
+            Eterm::Tuple(elems) =>
+                Eterm::Tuple(elems.into_iter().map(Eterm::normalize).collect()),
+            Eterm::List(elems) =>
+                Eterm::List(elems.into_iter().map(Eterm::normalize).collect()),
+            Eterm::Map(map) =>
+                Eterm::Map(map.into_iter().map(|(k, v)| (k.normalize(), v.normalize())).collect()),
+            Eterm::Fun(mut fun) => {
+                fun.free_vars = fun.free_vars.into_iter().map(Eterm::normalize).collect();
+                Eterm::Fun(fun)
+            },
+            Eterm::NewFun(mut fun) => {
+                fun.free_vars = fun.free_vars.into_iter().map(Eterm::normalize).collect();
+                Eterm::NewFun(fun)
+            },
+            other => other,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Reference {
     node: Atom,
     id: Vec<u8>,
-    creation: u8
+    creation: u32
 }
 #[derive(Debug, PartialEq, Clone)]
 pub struct Port {
     node: Atom,
-    id: u32,
-    creation: u8,
+    id: u64,
+    creation: u32,
 }
 #[derive(Debug, PartialEq, Clone)]
 pub struct Pid {
     node: Atom,
     id: u32,
     serial: u32,                // maybe [u8, ..4]?
-    creation: u8,
+    creation: u32,
 }
 #[derive(Debug, PartialEq, Clone)]
 pub struct Fun {
@@ -137,6 +290,178 @@ pub struct BitBinary {                 // bit_binary; maybe implement .to_bitv()
     data: Vec<u8>,
 }
 
+// Constructors validate the same invariants encoder does, so a constructed
+// value is always encodable (given suitable `IdentifierTags`).
+impl Reference {
+    // id is 1..65535 words, 3 for references made by OTP
+    pub fn new<S: Into<Atom>>(node: S, id: &[u32], creation: u32) -> Result<Reference, Error> {
+        let node = node.into();
+        check_atom(&node)?;
+        let id: Vec<u8> = id.iter().flat_map(|word| word.to_be_bytes()).collect();
+        check_reference_id(&id)?;
+        Ok(Reference{node, id, creation})
+    }
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+    pub fn id(&self) -> Vec<u32> {
+        self.id.chunks(4).map(|w| u32::from_be_bytes([w[0], w[1], w[2], w[3]])).collect()
+    }
+    pub fn creation(&self) -> u32 {
+        self.creation
+    }
+}
+
+impl Port {
+    pub fn new<S: Into<Atom>>(node: S, id: u64, creation: u32) -> Result<Port, Error> {
+        let node = node.into();
+        check_atom(&node)?;
+        Ok(Port{node, id, creation})
+    }
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+    pub fn creation(&self) -> u32 {
+        self.creation
+    }
+}
+
+impl Pid {
+    pub fn new<S: Into<Atom>>(node: S, id: u32, serial: u32, creation: u32) -> Result<Pid, Error> {
+        let node = node.into();
+        check_atom(&node)?;
+        Ok(Pid{node, id, serial, creation})
+    }
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+    pub fn creation(&self) -> u32 {
+        self.creation
+    }
+}
+
+impl Fun {
+    // no free variables; add them with `with_free_vars`
+    pub fn new<S: Into<Atom>>(pid: Pid, module: S, index: u32, uniq: u32) -> Result<Fun, Error> {
+        let module = module.into();
+        check_atom(&module)?;
+        Ok(Fun{pid, module, index, uniq, free_vars: Vec::new()})
+    }
+    pub fn with_free_vars(mut self, free_vars: Vec<Eterm>) -> Fun {
+        self.free_vars = free_vars;
+        self
+    }
+    pub fn pid(&self) -> &Pid {
+        &self.pid
+    }
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    pub fn uniq(&self) -> u32 {
+        self.uniq
+    }
+    pub fn free_vars(&self) -> &[Eterm] {
+        &self.free_vars
+    }
+}
+
+impl NewFun {
+    // uniq is 16 bytes (MD5 of module); old_index, old_uniq are 0 and no free
+    // variables until set by `with_*` builders
+    pub fn new<S: Into<Atom>>(pid: Pid, module: S, arity: u8, index: u32, uniq: Vec<u8>)
+                              -> Result<NewFun, Error> {
+        let module = module.into();
+        check_atom(&module)?;
+        check_fun_uniq(&uniq)?;
+        Ok(NewFun{arity, uniq, index, module, old_index: 0, old_uniq: 0, pid,
+                  free_vars: Vec::new()})
+    }
+    pub fn with_old_index(mut self, old_index: u32) -> NewFun {
+        self.old_index = old_index;
+        self
+    }
+    pub fn with_old_uniq(mut self, old_uniq: u32) -> NewFun {
+        self.old_uniq = old_uniq;
+        self
+    }
+    pub fn with_free_vars(mut self, free_vars: Vec<Eterm>) -> NewFun {
+        self.free_vars = free_vars;
+        self
+    }
+    pub fn arity(&self) -> u8 {
+        self.arity
+    }
+    pub fn uniq(&self) -> &[u8] {
+        &self.uniq
+    }
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+    pub fn old_index(&self) -> u32 {
+        self.old_index
+    }
+    pub fn old_uniq(&self) -> u32 {
+        self.old_uniq
+    }
+    pub fn pid(&self) -> &Pid {
+        &self.pid
+    }
+    pub fn free_vars(&self) -> &[Eterm] {
+        &self.free_vars
+    }
+}
+
+impl Export {
+    pub fn new<S: Into<Atom>, F: Into<Atom>>(module: S, function: F, arity: u8) -> Result<Export, Error> {
+        let (module, function) = (module.into(), function.into());
+        check_atom(&module)?;
+        check_atom(&function)?;
+        Ok(Export{module, function, arity})
+    }
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+    pub fn function(&self) -> &str {
+        &self.function
+    }
+    pub fn arity(&self) -> u8 {
+        self.arity
+    }
+}
+
+impl BitBinary {
+    // `bits` (1..8) is the number of used high bits of the last byte
+    pub fn new(data: Vec<u8>, bits: u8) -> Result<BitBinary, Error> {
+        check_bit_binary(bits, &data)?;
+        Ok(BitBinary{bits, data})
+    }
+    pub fn bits(&self) -> u8 {
+        self.bits
+    }
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+    // Size in bits
+    pub fn bit_len(&self) -> usize {
+        (self.data.len() - 1) * 8 + self.bits as usize
+    }
+}
+
 
 #[derive(Debug)]
 pub enum Error {
@@ -145,6 +470,21 @@ pub enum Error {
     ByteorderUnexpectedEOF,         // byteorder error
     BadFloat(ParseFloatError), // invalid float, encoded as string
     Io(io::Error),                  // io error
+    FrameTooLarge(usize, usize),    // port frame size exceeds packet header limit (size, limit)
+    BadPrelude(u8),                 // term doesn't start with 131
+    AtomTooLong(usize),             // atom has more than 255 characters
+    BadReferenceId(usize),          // reference id isn't 1..65535 of 4-byte words (length in bytes)
+    BadFunUniq(usize),              // NEW_FUN_EXT uniq should be 16 bytes (actual length)
+    NonFiniteFloat(f64),            // NaN and infinities aren't Erlang floats
+    CreationTooLarge(u32),          // creation doesn't fit into 8 bits of legacy pid/port/ref tags
+    PortIdTooLarge(u64),            // 64-bit port id requires IdentifierTags::V4
+    BadBitBinary(u8, usize),        // bits should be 1..8 and data non-empty (bits, data length)
+    EmptyList,                      // List has no tail element
+    LengthTooLarge(usize),          // container or binary has more than u32::MAX elements
+    AtomTableFull(usize),           // decoded atom doesn't fit into AtomTable (limit)
+    Syntax(usize, usize, String),   // bad Erlang term text (line, column, message)
+    DuplicateKey(Eterm),            // decoded map has the same key twice
+    TooDeep(usize),                 // decoded term is nested deeper than the limit
 }
 
 impl From<io::Error> for Error {
@@ -160,12 +500,27 @@ impl error::Error for Error {
             Error::UnexpectedTerm(_) => "Expected other term as a part of other complex term",
             Error::UnknownTag(_) => "Unknown term tag ID",
             Error::ByteorderUnexpectedEOF => "Not enough bytes to parse multibyte value",
-            Error::Io(ref err) => error::Error::description(err),
+            Error::Io(_) => "I/O error",
+            Error::FrameTooLarge(..) => "Port frame doesn't fit into packet header",
+            Error::BadPrelude(_) => "Bad external term format version",
+            Error::AtomTooLong(_) => "Atom is too long",
+            Error::BadReferenceId(_) => "Bad reference id length",
+            Error::BadFunUniq(_) => "Fun uniq should be 16 bytes",
+            Error::NonFiniteFloat(_) => "Float is NaN or infinity",
+            Error::CreationTooLarge(_) => "Creation doesn't fit into legacy identifier tags",
+            Error::PortIdTooLarge(_) => "Port id doesn't fit into selected port tag",
+            Error::BadBitBinary(..) => "Bad bit binary",
+            Error::EmptyList => "List without tail",
+            Error::LengthTooLarge(_) => "Length doesn't fit into 32 bits",
+            Error::AtomTableFull(_) => "Atom table is full",
+            Error::Syntax(..) => "Bad Erlang term syntax",
+            Error::DuplicateKey(_) => "Map has duplicate key",
+            Error::TooDeep(_) => "Term is nested too deep",
         }
     }
-    fn cause(&self) -> Option<&error::Error> {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            Error::Io(ref err) => err.cause(),
+            Error::Io(ref err) => Some(err),
             _ => None
         }
     }
@@ -178,6 +533,21 @@ impl fmt::Display for Error {
             Error::UnknownTag(ref val) => write!(f, "Unknown term tag ID: '{}'", val),
             Error::ByteorderUnexpectedEOF => write!(f, "Not enough bytes to parse multibyte value"),
             Error::Io(ref err) => err.fmt(f),
+            Error::FrameTooLarge(size, limit) => write!(f, "Frame of {} bytes exceeds limit of {} bytes", size, limit),
+            Error::BadPrelude(ref val) => write!(f, "Expected version byte 131, got '{}'", val),
+            Error::AtomTooLong(len) => write!(f, "Atom of {} characters exceeds limit of 255", len),
+            Error::BadReferenceId(len) => write!(f, "Reference id of {} bytes isn't a non-empty sequence of 4-byte words", len),
+            Error::BadFunUniq(len) => write!(f, "Fun uniq is {} bytes, expected 16", len),
+            Error::NonFiniteFloat(val) => write!(f, "Float '{}' can't be encoded", val),
+            Error::CreationTooLarge(val) => write!(f, "Creation {} doesn't fit into 8 bits, use newer IdentifierTags", val),
+            Error::PortIdTooLarge(val) => write!(f, "Port id {} doesn't fit into 32 bits, use IdentifierTags::V4", val),
+            Error::BadBitBinary(bits, len) => write!(f, "Bit binary of {} bytes with {} bits in last byte", len, bits),
+            Error::EmptyList => write!(f, "List should end with a tail element (usually Nil)"),
+            Error::LengthTooLarge(len) => write!(f, "Length {} exceeds u32::MAX", len),
+            Error::AtomTableFull(limit) => write!(f, "Atom table limit of {} atoms is reached", limit),
+            Error::Syntax(line, column, ref msg) => write!(f, "Syntax error at line {}, column {}: {}", line, column, msg),
+            Error::DuplicateKey(ref key) => write!(f, "Map has key {} more than once", key),
+            Error::TooDeep(limit) => write!(f, "Term is nested deeper than {} levels", limit),
         }
     }
 }
@@ -186,15 +556,18 @@ pub type DecodeResult = Result<Eterm, Error>;
 
 pub struct Decoder<'a, T: ?Sized + io::Read + 'a> {
     rdr: &'a mut T,
+    options: DecoderOptions,
+    atom_buf: Vec<u8>,
+    depth: usize,               // nesting of the term being decoded
 }
 
 macro_rules! decode_some(
     ($e:expr, $($t:path),+ ) => (
         {
-            match try!($e._decode_tag()) {
+            match $e._decode_tag()? {
                 $(
                     $t =>
-                        try!($e.decode_concrete_term($t)),
+                        $e.decode_concrete_term($t)?,
                     )+
                     bad =>
                     return Err(Error::UnexpectedTerm(bad))
@@ -205,85 +578,159 @@ macro_rules! decode_some(
 
 impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
     pub fn new(rdr: &'a mut T) -> Decoder<'a, T> {
-        Decoder{rdr: rdr}
+        Decoder::with_options(rdr, DecoderOptions::default())
+    }
+    pub fn with_options(rdr: &'a mut T, options: DecoderOptions) -> Decoder<'a, T> {
+        Decoder{rdr, options, atom_buf: Vec::new(), depth: 0}
     }
     pub fn read_prelude(&mut self) -> Result<bool, Error> {
-        Ok(131 == try!(self.rdr.read_u8()))
+        Ok(131 == self.rdr.read_u8()?)
     }
     fn decode_small_integer(&mut self) -> DecodeResult {
-        Ok(Eterm::SmallInteger(try!(self.rdr.read_u8())))
+        Ok(Eterm::SmallInteger(self.rdr.read_u8()?))
     }
     fn decode_integer(&mut self) -> DecodeResult {
-        Ok(Eterm::Integer(try!(self.rdr.read_i32::<BigEndian>())))
+        Ok(Eterm::Integer(self.rdr.read_i32::<BigEndian>()?))
     }
     fn _read_exact(&mut self, len: u64) -> io::Result<Vec<u8>> {
         let mut buf = Vec::with_capacity(len as usize);
-        try!(io::copy(&mut self.rdr.take(len), &mut buf));
+        io::copy(&mut self.rdr.take(len), &mut buf)?;
         Ok(buf)
     }
     fn _read_str(&mut self, len: usize) -> io::Result<String> {
         let mut str_buf = String::with_capacity(len);
-        try!(self.rdr.take(len as u64).read_to_string(&mut str_buf));
+        self.rdr.take(len as u64).read_to_string(&mut str_buf)?;
         Ok(str_buf)
     }
     fn decode_float(&mut self) -> DecodeResult {
-        let float_str = try!(self._read_str(31));
-        let num = try!(float_str.parse::<f32>());
-        Ok(Eterm::Float(num as f64))
+        let float_str = self._read_str(31)?;
+        // "%.20e" padded with zeroes
+        let num = float_str.trim_end_matches('\0').parse::<f64>()?;
+        Ok(Eterm::Float(num))
     }
     fn _decode_any_atom(&mut self) -> DecodeResult {
-        match try!(self._decode_tag()) {
-            ErlTermTag::ATOM_EXT | ErlTermTag::ATOM_UTF8_EXT => self.decode_atom(),
-            ErlTermTag::SMALL_ATOM_EXT | ErlTermTag::SMALL_ATOM_UTF8_EXT => self.decode_small_atom(),
+        match self._decode_tag()? {
+            ErlTermTag::ATOM_EXT => self.decode_atom(),
+            ErlTermTag::ATOM_UTF8_EXT => self.decode_atom_utf8(),
+            ErlTermTag::SMALL_ATOM_EXT => self.decode_small_atom(),
+            ErlTermTag::SMALL_ATOM_UTF8_EXT => self.decode_small_atom_utf8(),
             tag =>
                 Err(Error::UnexpectedTerm(tag))
         }
     }
+    // Atom text is read into reused buffer and only allocated by atom table
+    // when it's new
+    fn _read_atom(&mut self, len: usize, latin1: bool) -> Result<Atom, Error> {
+        self._read_atom_text(len, latin1)?;
+        if latin1 && !self.atom_buf.is_ascii() {
+            let name: String = self.atom_buf.iter().map(|b| *b as char).collect();
+            return self.options.atom(&name);
+        }
+        // checked by _read_atom_text
+        let name = str::from_utf8(&self.atom_buf).unwrap_or_default();
+        self.options.atom(name)
+    }
+    // Reads atom text into atom_buf; utf8 text is checked
+    fn _read_atom_text(&mut self, len: usize, latin1: bool) -> Result<(), Error> {
+        self.atom_buf.clear();
+        self.rdr.take(len as u64).read_to_end(&mut self.atom_buf)?;
+        if self.atom_buf.len() != len {
+            return Err(Error::ByteorderUnexpectedEOF);
+        }
+        if !latin1 {
+            str::from_utf8(&self.atom_buf)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        }
+        Ok(())
+    }
     fn decode_atom(&mut self) -> DecodeResult {
-        let len = try!(self.rdr.read_u16::<BigEndian>());
-        let atom_str = try!(self._read_str(len as usize));
-        // XXX: data is in latin1 in case of ATOM_EXT
-        Ok(Eterm::Atom(atom_str))
+        let len = self.rdr.read_u16::<BigEndian>()?;
+        Ok(Eterm::Atom(self._read_atom(len as usize, true)?))
+    }
+    fn decode_atom_utf8(&mut self) -> DecodeResult {
+        let len = self.rdr.read_u16::<BigEndian>()?;
+        Ok(Eterm::Atom(self._read_atom(len as usize, false)?))
     }
     fn decode_reference(&mut self) -> DecodeResult {
-        let node = match try!(self._decode_any_atom()) {
+        let node = match self._decode_any_atom()? {
             Eterm::Atom(a) => a,
             _ => unreachable!()
         };
-        let id = try!(self._read_exact(4));
-        let creation = try!(self.rdr.read_u8());
+        let id = self._read_exact(4)?;
+        let creation = self.rdr.read_u8()? as u32;
         Ok(Eterm::Reference(Reference {
-            node: node,
-            id: id,
-            creation: creation
+            node,
+            id,
+            creation
         }))
     }
     fn decode_port(&mut self) -> DecodeResult {
-        let node = match try!(self._decode_any_atom()) {
+        let node = match self._decode_any_atom()? {
+            Eterm::Atom(a) => a,
+            _ => unreachable!()
+        };
+        let id = self.rdr.read_u32::<BigEndian>()? as u64;
+        let creation = self.rdr.read_u8()? as u32;
+        Ok(Eterm::Port(Port {
+            node,
+            id,
+            creation
+        }))
+    }
+    fn decode_new_port(&mut self) -> DecodeResult {
+        let node = match self._decode_any_atom()? {
             Eterm::Atom(a) => a,
             _ => unreachable!()
         };
-        let id = try!(self.rdr.read_u32::<BigEndian>());
-        let creation = try!(self.rdr.read_u8());
+        let id = self.rdr.read_u32::<BigEndian>()? as u64;
+        let creation = self.rdr.read_u32::<BigEndian>()?;
         Ok(Eterm::Port(Port {
-            node: node,
-            id: id,
-            creation: creation
+            node,
+            id,
+            creation
+        }))
+    }
+    fn decode_v4_port(&mut self) -> DecodeResult {
+        let node = match self._decode_any_atom()? {
+            Eterm::Atom(a) => a,
+            _ => unreachable!()
+        };
+        let id = self.rdr.read_u64::<BigEndian>()?;
+        let creation = self.rdr.read_u32::<BigEndian>()?;
+        Ok(Eterm::Port(Port {
+            node,
+            id,
+            creation
         }))
     }
     fn decode_pid(&mut self) -> DecodeResult {
-        let node = match try!(self._decode_any_atom()) {
+        let node = match self._decode_any_atom()? {
+            Eterm::Atom(a) => a,
+            _ => unreachable!()
+        };
+        let id = self.rdr.read_u32::<BigEndian>()?;
+        let serial = self.rdr.read_u32::<BigEndian>()?;
+        let creation = self.rdr.read_u8()? as u32;
+        Ok(Eterm::Pid(Pid {
+            node,
+            id,
+            serial,
+            creation
+        }))
+    }
+    fn decode_new_pid(&mut self) -> DecodeResult {
+        let node = match self._decode_any_atom()? {
             Eterm::Atom(a) => a,
             _ => unreachable!()
         };
-        let id = try!(self.rdr.read_u32::<BigEndian>());
-        let serial = try!(self.rdr.read_u32::<BigEndian>());
-        let creation = try!(self.rdr.read_u8());
+        let id = self.rdr.read_u32::<BigEndian>()?;
+        let serial = self.rdr.read_u32::<BigEndian>()?;
+        let creation = self.rdr.read_u32::<BigEndian>()?;
         Ok(Eterm::Pid(Pid {
-            node: node,
-            id: id,
-            serial: serial,
-            creation: creation
+            node,
+            id,
+            serial,
+            creation
         }))
     }
 
@@ -291,10 +738,10 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
         self.rdr.read_u8()
     }
     fn decode_small_tuple(&mut self) -> DecodeResult {
-        let arity = try!(self._decode_small_tuple_arity());
+        let arity = self._decode_small_tuple_arity()?;
         let mut tuple: Tuple = Vec::with_capacity(arity as usize);
         for _ in 0..arity {
-            let term = try!(self.decode_term());
+            let term = self.decode_term()?;
             tuple.push(term)
         }
         Ok(Eterm::Tuple(tuple))
@@ -304,10 +751,10 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
         self.rdr.read_u32::<BigEndian>()
     }
     fn decode_large_tuple(&mut self) -> DecodeResult {
-        let arity = try!(self._decode_large_tuple_arity());
+        let arity = self._decode_large_tuple_arity()?;
         let mut tuple: Tuple = Vec::with_capacity(arity as usize);
         for _ in 0..arity {
-            let term = try!(self.decode_term());
+            let term = self.decode_term()?;
             tuple.push(term)
         }
         Ok(Eterm::Tuple(tuple))
@@ -317,21 +764,31 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
         self.rdr.read_u32::<BigEndian>()
     }
     fn decode_map(&mut self) -> DecodeResult {
-        let arity: u32 = try!(self._decode_map_arity());
-        let mut map: Map = Vec::with_capacity(arity as usize);
+        let arity: u32 = self._decode_map_arity()?;
+        let mut pairs = Vec::with_capacity(arity as usize);
         for _ in 0..arity {
-            let key = try!(self.decode_term());
-            let val = try!(self.decode_term());
-            map.push((key, val))
+            let key = self.decode_term()?;
+            let val = self.decode_term()?;
+            pairs.push((key, val));
         }
-        Ok(Eterm::Map(map))
+        // binary_to_term/1 rejects maps with duplicate keys too
+        ErlMap::from_unique(pairs).map(Eterm::Map).map_err(Error::DuplicateKey)
     }
     fn decode_nil(&mut self) -> DecodeResult {
         Ok(Eterm::Nil)
     }
     fn decode_string(&mut self) -> DecodeResult {
-        let len = try!(self.rdr.read_u16::<BigEndian>());
-        Ok(Eterm::String(try!(self._read_exact(len as u64))))
+        let len = self.rdr.read_u16::<BigEndian>()?;
+        let bytes = self._read_exact(len as u64)?;
+        if !self.options.strings_as_lists {
+            return Ok(Eterm::String(bytes));
+        }
+        if bytes.is_empty() {
+            return Ok(Eterm::Nil);
+        }
+        let mut list: Vec<Eterm> = bytes.into_iter().map(Eterm::SmallInteger).collect();
+        list.push(Eterm::Nil);
+        Ok(Eterm::List(list))
     }
 
     fn _decode_list_len(&mut self) -> io::Result<u32> {
@@ -339,63 +796,100 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
     }
     fn decode_list(&mut self) -> DecodeResult {
         // XXX: should we push Nil as last element or may ignore it?
-        let len = try!(self._decode_list_len()) + 1;
+        let len = self._decode_list_len()? + 1;
         let mut list = Vec::with_capacity(len as usize);
         for _ in 0..len {
-            let term = try!(self.decode_term());
+            let term = self.decode_term()?;
             list.push(term)
         }
         Ok(Eterm::List(list))
     }
     fn decode_binary(&mut self) -> DecodeResult {
-        let len = try!(self.rdr.read_u32::<BigEndian>());
-        Ok(Eterm::Binary(try!(self._read_exact(len as u64))))
+        let len = self.rdr.read_u32::<BigEndian>()?;
+        Ok(Eterm::Binary(self._read_exact(len as u64)?))
     }
     fn _decode_big(&mut self, n: usize) -> DecodeResult {
-        let sign_int = try!(self.rdr.read_u8());
+        let sign_int = self.rdr.read_u8()?;
         let sign = if sign_int == 0 {
             bigint::Sign::Plus
         } else {
             bigint::Sign::Minus
         };
-        let bytes = try!(self._read_exact(n as u64));
+        let bytes = self._read_exact(n as u64)?;
         Ok(Eterm::BigNum(bigint::BigInt::from_bytes_le(sign, bytes.as_ref())))
     }
     fn decode_small_big(&mut self) -> DecodeResult {
-        let n = try!(self.rdr.read_u8());
+        let n = self.rdr.read_u8()?;
         self._decode_big(n as usize)
     }
     fn decode_large_big(&mut self) -> DecodeResult {
-        let n = try!(self.rdr.read_u32::<BigEndian>());
+        let n = self.rdr.read_u32::<BigEndian>()?;
         self._decode_big(n as usize)
     }
     fn decode_new_reference(&mut self) -> DecodeResult {
-        let len = try!(self.rdr.read_u16::<BigEndian>()) as u64;
-        let node = match try!(self._decode_any_atom()) {
+        let len = self.rdr.read_u16::<BigEndian>()? as u64;
+        let node = match self._decode_any_atom()? {
+            Eterm::Atom(a) => a,
+            _ => unreachable!()
+        };
+        let creation = self.rdr.read_u8()? as u32;
+        let id = self._read_exact(4 * len)?;
+        Ok(Eterm::Reference(Reference {
+            node,
+            id, // here id should be Vec<u32>, but since it's not interpreted, leave it as is
+            creation
+        }))
+    }
+    fn decode_newer_reference(&mut self) -> DecodeResult {
+        let len = self.rdr.read_u16::<BigEndian>()? as u64;
+        let node = match self._decode_any_atom()? {
             Eterm::Atom(a) => a,
             _ => unreachable!()
         };
-        let creation = try!(self.rdr.read_u8());
-        let id = try!(self._read_exact(4 * len));
+        let creation = self.rdr.read_u32::<BigEndian>()?;
+        let id = self._read_exact(4 * len)?;
         Ok(Eterm::Reference(Reference {
-            node: node,
-            id: id, // here id should be Vec<u32>, but since it's not interpreted, leave it as is
-            creation: creation
+            node,
+            id,
+            creation
         }))
     }
+    fn decode_compressed(&mut self) -> DecodeResult {
+        let mut rdr = io::Cursor::new(self._read_compressed()?);
+        let mut decoder = Decoder::with_options(&mut rdr, self.options.clone());
+        // compressed term is at the same depth as its wrapper
+        decoder.depth = self.depth - 1;
+        decoder.decode_term()
+    }
+    // Inflates payload of COMPRESSED term
+    fn _read_compressed(&mut self) -> Result<Vec<u8>, Error> {
+        let size = self.rdr.read_u32::<BigEndian>()? as usize;
+        let mut data = Vec::with_capacity(size);
+        {
+            // 1-byte buffer, so nothing after the end of zlib stream is consumed
+            let buffered = io::BufReader::with_capacity(1, &mut *self.rdr);
+            ZlibDecoder::new(buffered).read_to_end(&mut data)?;
+        }
+        if data.len() != size {
+            return Err(Error::ByteorderUnexpectedEOF);
+        }
+        Ok(data)
+    }
     fn decode_small_atom(&mut self) -> DecodeResult {
-        let len = try!(self.rdr.read_u8());
-        let atom_str = try!(self._read_str(len as usize));
-        // XXX: data is in latin1 in case of SMALL_ATOM_EXT
-        Ok(Eterm::Atom(atom_str))
+        let len = self.rdr.read_u8()?;
+        Ok(Eterm::Atom(self._read_atom(len as usize, true)?))
+    }
+    fn decode_small_atom_utf8(&mut self) -> DecodeResult {
+        let len = self.rdr.read_u8()?;
+        Ok(Eterm::Atom(self._read_atom(len as usize, false)?))
     }
     fn decode_fun(&mut self) -> DecodeResult {
-        let num_free = try!(self.rdr.read_u32::<BigEndian>());
-        let pid = match decode_some!(self, ErlTermTag::PID_EXT) {
+        let num_free = self.rdr.read_u32::<BigEndian>()?;
+        let pid = match decode_some!(self, ErlTermTag::PID_EXT, ErlTermTag::NEW_PID_EXT) {
             Eterm::Pid(pid) => pid,
             _ => unreachable!()
         };
-        let module = match try!(self._decode_any_atom()) {
+        let module = match self._decode_any_atom()? {
             Eterm::Atom(atom) => atom,
             _ => unreachable!()
         };
@@ -411,24 +905,24 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
         };
         let mut free_vars = Vec::<Eterm>::with_capacity(num_free as usize);
         for _ in 0..num_free {
-            free_vars.push(try!(self.decode_term()));
+            free_vars.push(self.decode_term()?);
         }
         Ok(Eterm::Fun(Fun {
-            pid: pid,
-            module: module,
-            index: index,
-            uniq: uniq,
-            free_vars: free_vars,
+            pid,
+            module,
+            index,
+            uniq,
+            free_vars,
         }))
     }
     fn decode_new_fun(&mut self) -> DecodeResult {
-        let _size = try!(self.rdr.read_u32::<BigEndian>());
-        let arity = try!(self.rdr.read_u8());
-        let uniq = try!(self._read_exact(16));
-        let index = try!(self.rdr.read_u32::<BigEndian>());
-        let num_free = try!(self.rdr.read_u32::<BigEndian>());
+        let _size = self.rdr.read_u32::<BigEndian>()?;
+        let arity = self.rdr.read_u8()?;
+        let uniq = self._read_exact(16)?;
+        let index = self.rdr.read_u32::<BigEndian>()?;
+        let num_free = self.rdr.read_u32::<BigEndian>()?;
 
-        let module = match try!(self._decode_any_atom()) {
+        let module = match self._decode_any_atom()? {
             Eterm::Atom(atom) => atom,
             _ => unreachable!()
         };
@@ -442,31 +936,31 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
             Eterm::Integer(uq) => uq as u32,
             _ => unreachable!()
         };
-        let pid = match decode_some!(self, ErlTermTag::PID_EXT) {
+        let pid = match decode_some!(self, ErlTermTag::PID_EXT, ErlTermTag::NEW_PID_EXT) {
             Eterm::Pid(pid) => pid,
             _ => unreachable!()
         };
         let mut free_vars = Vec::<Eterm>::with_capacity(num_free as usize);
         for _ in 0..num_free {
-            free_vars.push(try!(self.decode_term()));
+            free_vars.push(self.decode_term()?);
         }
         Ok(Eterm::NewFun(NewFun {
-            arity: arity,
-            uniq: uniq,
-            index: index,
-            module: module,
-            old_index: old_index,
-            old_uniq: old_uniq,
-            pid: pid,
-            free_vars: free_vars,
+            arity,
+            uniq,
+            index,
+            module,
+            old_index,
+            old_uniq,
+            pid,
+            free_vars,
         }))
     }
     fn decode_export(&mut self) -> DecodeResult {
-        let module = match try!(self._decode_any_atom()) {
+        let module = match self._decode_any_atom()? {
             Eterm::Atom(atom) => atom,
             _ => unreachable!()
         };
-        let function = match try!(self._decode_any_atom()) {
+        let function = match self._decode_any_atom()? {
             Eterm::Atom(atom) => atom,
             _ => unreachable!()
         };
@@ -475,26 +969,34 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
             _ => unreachable!()
         };
         Ok(Eterm::Export(Export {
-            module: module,
-            function: function,
-            arity: arity, // arity > u8 possible in practice
+            module,
+            function,
+            arity, // arity > u8 possible in practice
         }))
     }
+    // Reads length and number of bits in the last byte (1..8); shared with validator
+    fn _read_bit_binary_header(&mut self) -> Result<(u32, u8), Error> {
+        let len = self.rdr.read_u32::<BigEndian>()?;
+        let bits = self.rdr.read_u8()?;
+        if bits == 0 || bits > 8 || len == 0 {
+            return Err(Error::UnexpectedTerm(ErlTermTag::BIT_BINARY_EXT));
+        }
+        Ok((len, bits))
+    }
     fn decode_bit_binary(&mut self) -> DecodeResult {
-        let len = try!(self.rdr.read_u32::<BigEndian>());
-        let bits = try!(self.rdr.read_u8());
+        let (len, bits) = self._read_bit_binary_header()?;
         Ok(Eterm::BitBinary(BitBinary {
-            bits: bits,
-            data: try!(self._read_exact(len as u64)),
+            bits,
+            data: self._read_exact(len as u64)?,
         }))
     }
     fn decode_new_float(&mut self) -> DecodeResult {
-        Ok(Eterm::Float(try!(self.rdr.read_f64::<BigEndian>())))
+        Ok(Eterm::Float(self.rdr.read_f64::<BigEndian>()?))
     }
 
 
     fn _decode_tag(&mut self) -> Result<ErlTermTag, Error> {
-        let int_tag = try!(self.rdr.read_u8());
+        let int_tag = self.rdr.read_u8()?;
         let tag: Option<ErlTermTag> = ErlTermTag::from_u8(int_tag);
         match tag {
             Some(t) => Ok(t),
@@ -503,15 +1005,27 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
         }
     }
     pub fn decode_term(&mut self) -> DecodeResult {
-        let tag = try!(self._decode_tag());
-        self.decode_concrete_term(tag)
+        let tag = self._decode_tag()?;
+        self._enter()?;
+        let term = self.decode_concrete_term(tag);
+        self.depth -= 1;
+        term
+    }
+    // Protects the stack from deeply nested input
+    fn _enter(&mut self) -> Result<(), Error> {
+        if self.depth >= self.options.max_depth {
+            return Err(Error::TooDeep(self.options.max_depth));
+        }
+        self.depth += 1;
+        Ok(())
     }
     fn decode_concrete_term(&mut self, tag: ErlTermTag) -> DecodeResult {
         match tag {
             ErlTermTag::SMALL_INTEGER_EXT => self.decode_small_integer(),
             ErlTermTag::INTEGER_EXT => self.decode_integer(),
             ErlTermTag::FLOAT_EXT => self.decode_float(),
-            ErlTermTag::ATOM_EXT | ErlTermTag::ATOM_UTF8_EXT => self.decode_atom(),
+            ErlTermTag::ATOM_EXT => self.decode_atom(),
+            ErlTermTag::ATOM_UTF8_EXT => self.decode_atom_utf8(),
             ErlTermTag::REFERENCE_EXT => self.decode_reference(),
             ErlTermTag::PORT_EXT => self.decode_port(),
             ErlTermTag::PID_EXT => self.decode_pid(),
@@ -525,312 +1039,685 @@ impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
             ErlTermTag::SMALL_BIG_EXT => self.decode_small_big(),
             ErlTermTag::LARGE_BIG_EXT => self.decode_large_big(),
             ErlTermTag::NEW_REFERENCE_EXT => self.decode_new_reference(),
-            ErlTermTag::SMALL_ATOM_EXT | ErlTermTag::SMALL_ATOM_UTF8_EXT => self.decode_small_atom(),
+            ErlTermTag::SMALL_ATOM_EXT => self.decode_small_atom(),
+            ErlTermTag::SMALL_ATOM_UTF8_EXT => self.decode_small_atom_utf8(),
             ErlTermTag::FUN_EXT => self.decode_fun(),
             ErlTermTag::NEW_FUN_EXT => self.decode_new_fun(),
             ErlTermTag::EXPORT_EXT => self.decode_export(),
             ErlTermTag::BIT_BINARY_EXT => self.decode_bit_binary(),
             ErlTermTag::NEW_FLOAT_EXT => self.decode_new_float(),
+            ErlTermTag::NEW_PID_EXT => self.decode_new_pid(),
+            ErlTermTag::NEW_PORT_EXT => self.decode_new_port(),
+            ErlTermTag::NEWER_REFERENCE_EXT => self.decode_newer_reference(),
+            ErlTermTag::V4_PORT_EXT => self.decode_v4_port(),
+            ErlTermTag::COMPRESSED => self.decode_compressed(),
+        }
+    }
+}
+
+pub type EncodeResult = Result<usize, Error>; // number of bytes written
+type WriteResult = Result<(), Error>;
+
+// Counts bytes passed to the underlying writer
+struct CountingWriter<W> {
+    inner: W,
+    count: usize,
+}
+
+impl<W: io::Write> io::Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written;
+        Ok(written)
+    }
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.count += buf.len();
+        Ok(())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Overwrites bytes, which were written `back` bytes before the current position
+type Patcher<W> = fn(&mut W, usize, &[u8]) -> io::Result<()>;
+
+fn patch_vec<W: AsMut<Vec<u8>>>(wrtr: &mut W, back: usize, bytes: &[u8]) -> io::Result<()> {
+    let vec = wrtr.as_mut();
+    let pos = vec.len() - back;
+    vec[pos..pos + bytes.len()].copy_from_slice(bytes);
+    Ok(())
+}
+
+fn patch_seek<W: io::Write + io::Seek>(wrtr: &mut W, back: usize, bytes: &[u8]) -> io::Result<()> {
+    wrtr.seek(io::SeekFrom::Current(-(back as i64)))?;
+    wrtr.write_all(bytes)?;
+    wrtr.seek(io::SeekFrom::Current((back - bytes.len()) as i64))?;
+    Ok(())
+}
+
+pub struct Encoder<W: io::Write> {
+    wrtr: CountingWriter<W>,
+    options: EncoderOptions,
+    patcher: Option<Patcher<W>>,
+    // (offset, data address) of binaries, left out of output by `to_iovec`
+    gaps: Option<Vec<(usize, usize)>>,
+}
+
+
+// Integer in 0..255, whichever variant holds it
+fn byte_value(term: &Eterm) -> Option<u8> {
+    match *term {
+        Eterm::SmallInteger(num) => Some(num),
+        Eterm::Integer(num) => num.to_u8(),
+        Eterm::BigNum(ref num) => num.to_u8(),
+        _ => None,
+    }
+}
+
+// Exact size of encoded term (without 131 prelude); nothing is written.
+pub fn encoded_size(term: &Eterm, options: &EncoderOptions) -> Result<usize, Error> {
+    let mut encoder = Encoder::new(io::sink(), options.clone());
+    // sizes written to sink don't matter
+    encoder.patcher = Some(|_, _, _| Ok(()));
+    encoder.encode_term(term)
+}
+
+// Like `term_to_binary/2`: encodes term with 131 prelude into a buffer of
+// exact size (compressed terms aren't sized in advance).
+pub fn to_vec(term: &Eterm, options: &EncoderOptions) -> Result<Vec<u8>, Error> {
+    let capacity = match options.compression {
+        Some(_) => 0,
+        None => 1 + encoded_size(term, options)?,
+    };
+    let mut encoder = Encoder::with_vec(Vec::with_capacity(capacity), options.clone());
+    encoder.write_prelude()?;
+    encoder.encode_term(term)?;
+    Ok(encoder.into_inner())
+}
+
+// Binaries of this size and larger are referenced by `to_iovec`, not copied
+// (same as ERL_ONHEAP_BIN_LIMIT)
+const IOVEC_BINARY_LIMIT: usize = 64;
+
+#[derive(Debug, Clone)]
+enum IoPart<'a> {
+    Scratch(usize, usize),      // range of scratch buffer
+    Binary(&'a [u8]),           // Binary term's payload
+}
+
+// Encoded term, which refers to large binaries of the original term. Written
+// with `write_vectored`, like the result of `erlang:term_to_iovec/1`.
+#[derive(Debug, Clone)]
+pub struct IoVec<'a> {
+    scratch: Vec<u8>,
+    parts: Vec<IoPart<'a>>,
+}
+
+impl<'a> IoVec<'a> {
+    pub fn slices(&self) -> Vec<io::IoSlice<'_>> {
+        self.parts.iter().map(|part| match *part {
+            IoPart::Scratch(start, end) => io::IoSlice::new(&self.scratch[start..end]),
+            IoPart::Binary(data) => io::IoSlice::new(data),
+        }).collect()
+    }
+
+    // Total size in bytes
+    pub fn len(&self) -> usize {
+        self.slices().iter().map(|slice| slice.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.parts.is_empty()
+    }
+
+    pub fn write_to<W: io::Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut slices = self.slices();
+        let mut slices = &mut slices[..];
+        while !slices.is_empty() {
+            match writer.write_vectored(slices) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::WriteZero)),
+                Ok(n) => io::IoSlice::advance_slices(&mut slices, n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(self.len());
+        for slice in self.slices() {
+            buf.extend_from_slice(&slice);
         }
+        buf
     }
 }
 
-pub type EncodeResult = Result<(), Error>; // TODO: maybe return num bytes written?
+// Large binaries of term, by their data address
+fn collect_binaries<'a>(term: &'a Eterm, binaries: &mut HashMap<usize, &'a [u8]>) {
+    match *term {
+        Eterm::Binary(ref data) if data.len() >= IOVEC_BINARY_LIMIT => {
+            binaries.insert(data.as_ptr() as usize, &data[..]);
+        },
+        Eterm::Tuple(ref elems) | Eterm::List(ref elems) =>
+            for elem in elems.iter() {
+                collect_binaries(elem, binaries);
+            },
+        Eterm::Map(ref pairs) =>
+            for (key, val) in pairs.iter() {
+                collect_binaries(key, binaries);
+                collect_binaries(val, binaries);
+            },
+        Eterm::Fun(ref fun) =>
+            for var in fun.free_vars.iter() {
+                collect_binaries(var, binaries);
+            },
+        Eterm::NewFun(ref fun) =>
+            for var in fun.free_vars.iter() {
+                collect_binaries(var, binaries);
+            },
+        _ => (),
+    }
+}
 
-pub struct Encoder<'a> {
-    wrtr: &'a mut (io::Write + 'a),
-    use_utf8_atoms: bool,
-    use_small_atoms: bool,
-    fair_new_fun: bool,
-    //use_new_float: bool, (>=R11B)
+// Like `term_to_iovec/2`: encodes term with 131 prelude, but payloads of
+// large binaries aren't copied. Compressed term is a single buffer.
+pub fn to_iovec<'a>(term: &'a Eterm, options: &EncoderOptions) -> Result<IoVec<'a>, Error> {
+    let mut encoder = Encoder::new(Vec::new(), options.clone());
+    encoder.gaps = Some(Vec::new());
+    encoder.write_prelude()?;
+    encoder.encode_term(term)?;
+    let gaps = encoder.gaps.take().unwrap_or_default();
+    let scratch = encoder.into_inner();
+
+    let mut binaries = HashMap::new();
+    if !gaps.is_empty() {
+        collect_binaries(term, &mut binaries);
+    }
+    let mut parts = Vec::with_capacity(2 * gaps.len() + 1);
+    let mut start = 0;
+    for (offset, addr) in gaps {
+        if offset > start {
+            parts.push(IoPart::Scratch(start, offset));
+        }
+        parts.push(IoPart::Binary(binaries[&addr]));
+        start = offset;
+    }
+    if scratch.len() > start {
+        parts.push(IoPart::Scratch(start, scratch.len()));
+    }
+    Ok(IoVec{scratch, parts})
 }
 
 
-impl<'a> Encoder<'a> {
-    // TODO: asserts for overflows
+impl<W: io::Write> Encoder<W> {
+    pub fn new(writer: W, options: EncoderOptions) -> Encoder<W> {
+        Encoder{wrtr: CountingWriter{inner: writer, count: 0},
+                options,
+                patcher: None,
+                gaps: None}
+    }
+
+    // Encoders for `Vec<u8>` / `&mut Vec<u8>` and `Seek` writers don't need
+    // temporary buffers: NEW_FUN_EXT size is patched after the fun is written.
+    pub fn with_vec(writer: W, options: EncoderOptions) -> Encoder<W>
+        where W: AsMut<Vec<u8>> {
+        let mut encoder = Encoder::new(writer, options);
+        encoder.patcher = Some(patch_vec::<W>);
+        encoder
+    }
 
-    pub fn new(writer: &'a mut io::Write, utf8_atoms: bool, small_atoms: bool, fair_new_fun: bool) -> Encoder<'a> {
-        Encoder{wrtr: writer,
-                use_utf8_atoms: utf8_atoms,
-                use_small_atoms: small_atoms,
-                fair_new_fun: fair_new_fun}
+    pub fn with_seek(writer: W, options: EncoderOptions) -> Encoder<W>
+        where W: io::Seek {
+        let mut encoder = Encoder::new(writer, options);
+        encoder.patcher = Some(patch_seek::<W>);
+        encoder
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.wrtr.inner
+    }
+
+    // Returns underlying writer; it's not flushed
+    pub fn into_inner(self) -> W {
+        self.wrtr.inner
+    }
+
+    // Total number of bytes written by this encoder
+    pub fn bytes_written(&self) -> usize {
+        self.wrtr.count
     }
 
     pub fn write_prelude(&mut self) -> EncodeResult {
-        self.wrtr.write_u8(131).map_err(From::from)
+        self.wrtr.write_u8(131)?;
+        Ok(1)
     }
 
     pub fn flush(&mut self) -> io::Result<()> {
         self.wrtr.flush()
     }
 
-    fn encode_small_integer(&mut self, num: u8) -> EncodeResult {
+    fn encode_small_integer(&mut self, num: u8) -> WriteResult {
         self.wrtr.write_u8(num).map_err(From::from)
     }
-    fn encode_integer(&mut self, num: i32) -> EncodeResult {
+    fn encode_integer(&mut self, num: i32) -> WriteResult {
         self.wrtr.write_i32::<BigEndian>(num).map_err(From::from)
     }
-    fn encode_new_float(&mut self, num: f64) -> EncodeResult {
+    fn encode_new_float(&mut self, num: f64) -> WriteResult {
         self.wrtr.write_f64::<BigEndian>(num).map_err(From::from)
     }
-
-    fn _encode_str(&mut self, s: String) -> EncodeResult {
-        self.wrtr.write_all(s.as_bytes()).map_err(From::from)
+    fn encode_float(&mut self, num: f64) -> WriteResult {
+        // C's "%.20e": exponent has sign and at least 2 digits
+        // (num is finite, so there is always an exponent)
+        let formatted = format!("{:.20e}", num);
+        let pos = formatted.find('e').unwrap();
+        let exp = formatted[pos + 1..].parse::<i32>().unwrap();
+        let mut buf = format!("{}e{}{:02}", &formatted[..pos], if exp < 0 {'-'} else {'+'}, exp.abs())
+            .into_bytes();
+        buf.resize(31, 0);
+        self.wrtr.write_all(&buf).map_err(From::from)
+    }
+
+    fn encode_atom(&mut self, atom: &[u8]) -> WriteResult {
+        self.wrtr.write_u16::<BigEndian>(atom.len() as u16)?;
+        self.wrtr.write_all(atom).map_err(From::from)
+    }
+    fn encode_small_atom(&mut self, atom: &[u8]) -> WriteResult {
+        self.wrtr.write_u8(atom.len() as u8)?;
+        self.wrtr.write_all(atom).map_err(From::from)
+    }
+    fn _encode_any_atom(&mut self, atom: &str) -> WriteResult {
+        check_atom(atom)?;
+        let latin1 = if self.options.atom_encoding == AtomEncoding::Latin1 && atom.chars().all(|c| (c as u32) <= 0xFF) {
+            Some(atom.chars().map(|c| c as u8).collect::<Vec<u8>>())
+        } else {
+            None
+        };
+        match latin1 {
+            Some(bytes) => {
+                if bytes.len() <= 255 && self.options.small_atoms {
+                    self._encode_tag(ErlTermTag::SMALL_ATOM_EXT)?;
+                    self.encode_small_atom(&bytes)
+                } else {
+                    self._encode_tag(ErlTermTag::ATOM_EXT)?;
+                    self.encode_atom(&bytes)
+                }
+            },
+            None => {
+                // OTP writes non-latin1 atoms as utf8 even with minor_version 1
+                let fallback = self.options.atom_encoding == AtomEncoding::Latin1;
+                if atom.len() <= 255 && (self.options.small_atoms || fallback) {
+                    self._encode_tag(ErlTermTag::SMALL_ATOM_UTF8_EXT)?;
+                    self.encode_small_atom(atom.as_bytes())
+                } else {
+                    self._encode_tag(ErlTermTag::ATOM_UTF8_EXT)?;
+                    self.encode_atom(atom.as_bytes())
+                }
+            }
+        }
+    }
+    // Smallest tag OTP would use
+    fn _encode_any_integer(&mut self, num: i32) -> WriteResult {
+        if (0..=255).contains(&num) {
+            self._encode_tag(ErlTermTag::SMALL_INTEGER_EXT)?;
+            self.encode_small_integer(num as u8)
+        } else {
+            self._encode_tag(ErlTermTag::INTEGER_EXT)?;
+            self.encode_integer(num)
+        }
+    }
+    fn _encode_u32_as_integer(&mut self, num: u32) -> WriteResult {
+        if num <= 255 {
+            self._encode_tag(ErlTermTag::SMALL_INTEGER_EXT)?;
+            self.encode_small_integer(num as u8)
+        } else {
+            self._encode_tag(ErlTermTag::INTEGER_EXT)?;
+            self.encode_integer(num as i32)
+        }
     }
-    fn encode_atom(&mut self, atom: Atom) -> EncodeResult {
-        try!(self.wrtr.write_u16::<BigEndian>(atom.len() as u16));
-        self._encode_str(atom)
+    fn _big_creation(&self) -> bool {
+        self.options.identifier_tags != IdentifierTags::Legacy
     }
-    fn encode_small_atom(&mut self, atom: Atom) -> EncodeResult {
-        try!(self.wrtr.write_u8(atom.len() as u8));
-        self._encode_str(atom)
+    fn _encode_creation(&mut self, creation: u32) -> WriteResult {
+        check_creation(creation, &self.options)?;
+        if self._big_creation() {
+            self.wrtr.write_u32::<BigEndian>(creation).map_err(From::from)
+        } else {
+            self.wrtr.write_u8(creation as u8).map_err(From::from)
+        }
     }
-    fn encode_new_reference(&mut self, reference: Reference) -> EncodeResult {
-        let len = reference.id.len() / 4; // todo: ensure proper rounding, maybe (id.len() / 4) + if (id.len() % 4) == 0 {0} else {1}
-        try!(self.wrtr.write_u16::<BigEndian>(len as u16));
-        try!(self.encode_term(Eterm::Atom(reference.node)));
-        try!(self.wrtr.write_u8(reference.creation));
+    fn encode_new_reference(&mut self, reference: &Reference) -> WriteResult {
+        check_reference_id(&reference.id)?;
+        let len = reference.id.len() / 4;
+        self.wrtr.write_u16::<BigEndian>(len as u16)?;
+        self._encode_any_atom(&reference.node)?;
+        self._encode_creation(reference.creation)?;
         self.wrtr.write_all(reference.id.as_ref()).map_err(From::from)
     }
-    fn encode_port(&mut self, port: Port) -> EncodeResult {
-        try!(self.encode_term(Eterm::Atom(port.node)));
-        try!(self.wrtr.write_u32::<BigEndian>(port.id));
-        self.wrtr.write_u8(port.creation).map_err(From::from)
+    fn encode_port(&mut self, port: &Port) -> WriteResult {
+        check_port_id(port.id, &self.options)?;
+        self._encode_any_atom(&port.node)?;
+        self.wrtr.write_u32::<BigEndian>(port.id as u32)?;
+        self._encode_creation(port.creation)
     }
-    fn encode_pid(&mut self, pid: Pid) -> EncodeResult {
-        try!(self.encode_term(Eterm::Atom(pid.node)));
-        try!(self.wrtr.write_u32::<BigEndian>(pid.id));
-        try!(self.wrtr.write_u32::<BigEndian>(pid.serial));
-        self.wrtr.write_u8(pid.creation).map_err(From::from)
+    fn encode_v4_port(&mut self, port: &Port) -> WriteResult {
+        self._encode_any_atom(&port.node)?;
+        self.wrtr.write_u64::<BigEndian>(port.id)?;
+        self.wrtr.write_u32::<BigEndian>(port.creation).map_err(From::from)
+    }
+    fn encode_pid(&mut self, pid: &Pid) -> WriteResult {
+        self._encode_any_atom(&pid.node)?;
+        self.wrtr.write_u32::<BigEndian>(pid.id)?;
+        self.wrtr.write_u32::<BigEndian>(pid.serial)?;
+        self._encode_creation(pid.creation)
+    }
+    fn _encode_any_pid(&mut self, pid: &Pid) -> WriteResult {
+        self._encode_tag(if self._big_creation() {ErlTermTag::NEW_PID_EXT} else {ErlTermTag::PID_EXT})?;
+        self.encode_pid(pid)
     }
 
-    fn encode_small_tuple(&mut self, tuple: Vec<Eterm>) -> EncodeResult {
-        try!(self.wrtr.write_u8(tuple.len() as u8));
-        for term in tuple.into_iter() {
-            try!(self.encode_term(term));
+    fn encode_small_tuple(&mut self, tuple: &[Eterm]) -> WriteResult {
+        self.wrtr.write_u8(tuple.len() as u8)?;
+        for term in tuple.iter() {
+            self._encode_term(term)?;
         }
         Ok(())
     }
-    fn encode_large_tuple(&mut self, tuple: Vec<Eterm>) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>(tuple.len() as u32));
-        for term in tuple.into_iter() {
-            try!(self.encode_term(term));
+    fn encode_large_tuple(&mut self, tuple: &[Eterm]) -> WriteResult {
+        check_length(tuple.len())?;
+        self.wrtr.write_u32::<BigEndian>(tuple.len() as u32)?;
+        for term in tuple.iter() {
+            self._encode_term(term)?;
         }
         Ok(())
     }
-    fn encode_map(&mut self, map: Map) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>(map.len() as u32));
-        for (key, val) in map.into_iter() {
-            try!(self.encode_term(key));
-            try!(self.encode_term(val));
+    fn encode_map(&mut self, map: &ErlMap) -> WriteResult {
+        check_length(map.len())?;
+        self.wrtr.write_u32::<BigEndian>(map.len() as u32)?;
+        match self.options.map_order {
+            MapOrder::Preserve =>
+                for (key, val) in map.iter() {
+                    self._encode_term(key)?;
+                    self._encode_term(val)?;
+                },
+            MapOrder::Sorted =>
+                for (key, val) in map.sorted() {
+                    self._encode_term(key)?;
+                    self._encode_term(val)?;
+                },
         }
         Ok(())
     }
-    fn encode_string(&mut self, s: Vec<u8>) -> EncodeResult {
-        try!(self.wrtr.write_u16::<BigEndian>(s.len() as u16));
-        self.wrtr.write_all(s.as_ref()).map_err(From::from)
+    fn encode_string(&mut self, s: &[u8]) -> WriteResult {
+        self.wrtr.write_u16::<BigEndian>(s.len() as u16)?;
+        self.wrtr.write_all(s).map_err(From::from)
+    }
+    // Same choice as OTP makes: [] is NIL_EXT, short lists of bytes are
+    // STRING_EXT and everything else is LIST_EXT
+    fn _encode_any_string(&mut self, s: &[u8]) -> WriteResult {
+        if s.is_empty() {
+            self._encode_tag(ErlTermTag::NIL_EXT)
+        } else if s.len() <= u16::MAX as usize {
+            self._encode_tag(ErlTermTag::STRING_EXT)?;
+            self.encode_string(s)
+        } else {
+            check_length(s.len())?;
+            self._encode_tag(ErlTermTag::LIST_EXT)?;
+            self.wrtr.write_u32::<BigEndian>(s.len() as u32)?;
+            for byte in s.iter() {
+                self._encode_tag(ErlTermTag::SMALL_INTEGER_EXT)?;
+                self.encode_small_integer(*byte)?;
+            }
+            self._encode_tag(ErlTermTag::NIL_EXT)
+        }
     }
-    fn encode_list(&mut self, list: Vec<Eterm>) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>((list.len() - 1) as u32));
-        for term in list.into_iter() {
-            try!(self.encode_term(term));
+    fn _encode_any_list(&mut self, list: &[Eterm]) -> WriteResult {
+        check_list(list)?;
+        let (tail, heads) = list.split_last().unwrap();
+        if *tail == Eterm::Nil && heads.len() <= u16::MAX as usize {
+            let bytes: Option<Vec<u8>> = heads.iter().map(byte_value).collect();
+            if let Some(bytes) = bytes {
+                return self._encode_any_string(&bytes);
+            }
+        }
+        self._encode_tag(ErlTermTag::LIST_EXT)?;
+        self.encode_list(list)
+    }
+    fn encode_list(&mut self, list: &[Eterm]) -> WriteResult {
+        check_list(list)?;
+        self.wrtr.write_u32::<BigEndian>((list.len() - 1) as u32)?;
+        for term in list.iter() {
+            self._encode_term(term)?;
         }
         Ok(())
     }
 
-    fn encode_binary(&mut self, bin: Vec<u8>) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>(bin.len() as u32));
-        self.wrtr.write_all(bin.as_ref()).map_err(From::from)
+    fn encode_binary(&mut self, bin: &[u8]) -> WriteResult {
+        check_length(bin.len())?;
+        self.wrtr.write_u32::<BigEndian>(bin.len() as u32)?;
+        if let Some(ref mut gaps) = self.gaps {
+            if bin.len() >= IOVEC_BINARY_LIMIT {
+                gaps.push((self.wrtr.count, bin.as_ptr() as usize));
+                return Ok(());
+            }
+        }
+        self.wrtr.write_all(bin).map_err(From::from)
     }
 
-    fn _encode_big(&mut self, sign: bigint::Sign, bytes: Vec<u8>) -> EncodeResult {
-        try!(self.wrtr.write_u8(match sign {
-            bigint::Sign::Plus => 0,
+    fn _encode_big(&mut self, sign: bigint::Sign, bytes: &[u8]) -> WriteResult {
+        self.wrtr.write_u8(match sign {
+            bigint::Sign::Plus | bigint::Sign::NoSign => 0,
             bigint::Sign::Minus => 1,
-            _ => panic!("Invalid bignum sign")
-        }));
-        self.wrtr.write_all(bytes.as_ref()).map_err(From::from)
+        })?;
+        self.wrtr.write_all(bytes).map_err(From::from)
     }
-    fn encode_small_big(&mut self, sign: bigint::Sign, bytes: Vec<u8>) -> EncodeResult {
-        try!(self.wrtr.write_u8(bytes.len() as u8));
+    fn encode_small_big(&mut self, sign: bigint::Sign, bytes: &[u8]) -> WriteResult {
+        self.wrtr.write_u8(bytes.len() as u8)?;
         self._encode_big(sign, bytes)
     }
-    fn encode_large_big(&mut self, sign: bigint::Sign, bytes: Vec<u8>) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>(bytes.len() as u32));
+    fn encode_large_big(&mut self, sign: bigint::Sign, bytes: &[u8]) -> WriteResult {
+        check_length(bytes.len())?;
+        self.wrtr.write_u32::<BigEndian>(bytes.len() as u32)?;
         self._encode_big(sign, bytes)
     }
 
-    fn encode_fun(&mut self, fun: Fun) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>(fun.free_vars.len() as u32));
-        try!(self.encode_term(Eterm::Pid(fun.pid)));
-        try!(self.encode_term(Eterm::Atom(fun.module)));
-        try!(self.encode_term(
-            if fun.index <= 255 { Eterm::SmallInteger(fun.index as u8) }
-            else { Eterm::Integer(fun.index as i32) }));
-        try!(self.encode_term(
-            if fun.uniq <= 255 { Eterm::SmallInteger(fun.uniq as u8) }
-            else { Eterm::Integer(fun.uniq as i32) }));
-        for term in fun.free_vars.into_iter() {
-            try!(self.encode_term(term));
+    fn encode_fun(&mut self, fun: &Fun) -> WriteResult {
+        check_length(fun.free_vars.len())?;
+        self.wrtr.write_u32::<BigEndian>(fun.free_vars.len() as u32)?;
+        self._encode_any_pid(&fun.pid)?;
+        self._encode_any_atom(&fun.module)?;
+        self._encode_u32_as_integer(fun.index)?;
+        self._encode_u32_as_integer(fun.uniq)?;
+        for term in fun.free_vars.iter() {
+            self._encode_term(term)?;
         }
         Ok(())
     }
-    fn _encode_new_fun(&mut self, fun: NewFun) -> EncodeResult {
-        try!(self.wrtr.write_u8(fun.arity));
-        assert!(fun.uniq.len() == 16);
-        try!(self.wrtr.write_all(fun.uniq.as_ref()));
-        try!(self.wrtr.write_u32::<BigEndian>(fun.index));
-        try!(self.wrtr.write_u32::<BigEndian>(fun.free_vars.len() as u32));
-        try!(self.encode_term(Eterm::Atom(fun.module)));
-
-        let old_index_term = if fun.old_index <= 255 {
-            Eterm::SmallInteger(fun.old_index as u8)
-        } else {
-            Eterm::Integer(fun.old_index as i32)
-        };
-        try!(self.encode_term(old_index_term));
-
-        let old_uniq_term = if fun.old_uniq <= 255 {
-            Eterm::SmallInteger(fun.old_uniq as u8)
-        } else {
-            Eterm::Integer(fun.old_uniq as i32)
-        };
-        try!(self.encode_term(old_uniq_term));
-
-        try!(self.encode_term(Eterm::Pid(fun.pid)));
-
-        for term in fun.free_vars.into_iter() {
-            try!(self.encode_term(term));
+    fn _encode_new_fun(&mut self, fun: &NewFun) -> WriteResult {
+        check_fun_uniq(&fun.uniq)?;
+        check_length(fun.free_vars.len())?;
+        self.wrtr.write_u8(fun.arity)?;
+        self.wrtr.write_all(fun.uniq.as_ref())?;
+        self.wrtr.write_u32::<BigEndian>(fun.index)?;
+        self.wrtr.write_u32::<BigEndian>(fun.free_vars.len() as u32)?;
+        self._encode_any_atom(&fun.module)?;
+        self._encode_u32_as_integer(fun.old_index)?;
+        self._encode_u32_as_integer(fun.old_uniq)?;
+        self._encode_any_pid(&fun.pid)?;
+
+        for term in fun.free_vars.iter() {
+            self._encode_term(term)?;
         }
         Ok(())
     }
-    fn encode_new_fun(&mut self, fun: NewFun) -> EncodeResult {
-        // We serialize to temporary memory buffer to calculate encoded term size.
-        // Erlang itself in 'term_to_binary' does back-patching (see
-        // erts/emulator/beam/external.c#enc_term_int 'ENC_PATCH_FUN_SIZE'), but
-        // at the same time, in 'binary_to_term' this size u32 is just skipped!
-        // So, we make this configurable: do fair encoding or cheating with
+    fn encode_new_fun(&mut self, fun: &NewFun) -> WriteResult {
+        // Like Erlang's 'term_to_binary' (see erts/emulator/beam/external.c
+        // #enc_term_int 'ENC_PATCH_FUN_SIZE') we back-patch the size when
+        // writer allows it. Otherwise fun is serialized to temporary memory
+        // buffer to calculate its size. But 'binary_to_term' just skips this
+        // u32, so, we make this configurable: do fair encoding or cheating with
         // fake zero size.
-        if self.fair_new_fun {
-            let mut temp = Vec::new();
-            {
-                let mut encoder = Encoder::new(&mut temp, self.use_utf8_atoms, self.use_small_atoms, self.fair_new_fun);
-                try!(encoder._encode_new_fun(fun));
-            }
-            let size = temp.len();
-            // +4 is size itself
-            try!(self.wrtr.write_u32::<BigEndian>(4 + size as u32));
-            self.wrtr.write_all(temp.as_ref()).map_err(From::from)
-        } else {
+        if !self.options.fair_new_fun {
             // cheating - write 0, since binary_to_term don't use this (at least now, in 17.0)
-            try!(self.wrtr.write_u32::<BigEndian>(0));
-            self._encode_new_fun(fun)
+            self.wrtr.write_u32::<BigEndian>(0)?;
+            return self._encode_new_fun(fun);
+        }
+        match self.patcher {
+            Some(patch) => {
+                let start = self.wrtr.count;
+                self.wrtr.write_u32::<BigEndian>(0)?;
+                self._encode_new_fun(fun)?;
+                // size includes size field itself
+                let size = self.wrtr.count - start;
+                patch(&mut self.wrtr.inner, size, &(size as u32).to_be_bytes())
+                    .map_err(From::from)
+            },
+            None => {
+                let mut temp = Vec::new();
+                Encoder::with_vec(&mut temp, self.options.clone())._encode_new_fun(fun)?;
+                // +4 is size itself
+                self.wrtr.write_u32::<BigEndian>(4 + temp.len() as u32)?;
+                self.wrtr.write_all(temp.as_ref()).map_err(From::from)
+            }
         }
     }
-    fn encode_export(&mut self, export: Export) -> EncodeResult {
-        try!(self.encode_term(Eterm::Atom(export.module)));
-        try!(self.encode_term(Eterm::Atom(export.function)));
-        self.encode_term(Eterm::SmallInteger(export.arity))
+    fn encode_export(&mut self, export: &Export) -> WriteResult {
+        self._encode_any_atom(&export.module)?;
+        self._encode_any_atom(&export.function)?;
+        self._encode_tag(ErlTermTag::SMALL_INTEGER_EXT)?;
+        self.encode_small_integer(export.arity)
     }
-    fn encode_bit_binary(&mut self, bit_bin: BitBinary) -> EncodeResult {
-        try!(self.wrtr.write_u32::<BigEndian>(bit_bin.data.len() as u32));
-        try!(self.wrtr.write_u8(bit_bin.bits));
+    fn encode_bit_binary(&mut self, bit_bin: &BitBinary) -> WriteResult {
+        check_bit_binary(bit_bin.bits, &bit_bin.data)?;
+        self.wrtr.write_u32::<BigEndian>(bit_bin.data.len() as u32)?;
+        self.wrtr.write_u8(bit_bin.bits)?;
         self.wrtr.write_all(bit_bin.data.as_ref()).map_err(From::from)
     }
 
-    fn _encode_tag(&mut self, tag: ErlTermTag) -> EncodeResult {
+    fn _encode_tag(&mut self, tag: ErlTermTag) -> WriteResult {
         let int_tag = tag as u8;
         self.wrtr.write_u8(int_tag).map_err(From::from)
     }
-    pub fn encode_term(&mut self, term: Eterm) -> EncodeResult {
-        // XXX: maybe use &Eterm, not just Eterm?
-        match term {
+    // Accepts both `Eterm` and `&Eterm`; term is never copied.
+    // Returns number of bytes written.
+    pub fn encode_term<T: Borrow<Eterm>>(&mut self, term: T) -> EncodeResult {
+        let before = self.wrtr.count;
+        match self.options.compression {
+            Some(level) => self._encode_compressed(term.borrow(), level)?,
+            None => self._encode_term(term.borrow())?
+        }
+        Ok(self.wrtr.count - before)
+    }
+    fn _encode_compressed(&mut self, term: &Eterm, level: u32) -> WriteResult {
+        let mut raw = Vec::new();
+        Encoder::with_vec(&mut raw, self.options.clone().compressed(0)).encode_term(term)?;
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::new(level));
+        zlib.write_all(&raw)?;
+        let compressed = zlib.finish()?;
+        // like term_to_binary, fall back to uncompressed when it's not smaller
+        if 5 + compressed.len() < raw.len() {
+            self._encode_tag(ErlTermTag::COMPRESSED)?;
+            self.wrtr.write_u32::<BigEndian>(raw.len() as u32)?;
+            self.wrtr.write_all(&compressed).map_err(From::from)
+        } else {
+            self.wrtr.write_all(&raw).map_err(From::from)
+        }
+    }
+    fn _encode_term(&mut self, term: &Eterm) -> WriteResult {
+        match *term {
             Eterm::SmallInteger(num) => {
-                try!(self._encode_tag(ErlTermTag::SMALL_INTEGER_EXT));
+                self._encode_tag(ErlTermTag::SMALL_INTEGER_EXT)?;
                 self.encode_small_integer(num)
             },
-            Eterm::Integer(num) => {
-                try!(self._encode_tag(ErlTermTag::INTEGER_EXT));
-                self.encode_integer(num)
-            },
+            Eterm::Integer(num) =>
+                self._encode_any_integer(num),
             Eterm::Float(num) => {
-                try!(self._encode_tag(ErlTermTag::NEW_FLOAT_EXT));
-                self.encode_new_float(num)
-            },
-            Eterm::Atom(atom) => {
-                let use_utf8 = self.use_utf8_atoms;
-                let use_small = self.use_small_atoms;
-                if (atom.len() <= 255) && use_small {
-                    try!(self._encode_tag(if use_utf8 {ErlTermTag::SMALL_ATOM_UTF8_EXT} else {ErlTermTag::SMALL_ATOM_EXT}));
-                    self.encode_small_atom(atom)
-                } else {
-                    try!(self._encode_tag(if use_utf8 {ErlTermTag::ATOM_UTF8_EXT} else {ErlTermTag::ATOM_EXT}));
-                    self.encode_atom(atom)
+                check_float(num)?;
+                match self.options.float_format {
+                    FloatFormat::New => {
+                        self._encode_tag(ErlTermTag::NEW_FLOAT_EXT)?;
+                        self.encode_new_float(num)
+                    },
+                    FloatFormat::Old => {
+                        self._encode_tag(ErlTermTag::FLOAT_EXT)?;
+                        self.encode_float(num)
+                    }
                 }
             },
-            Eterm::Reference(reference) => {
-                try!(self._encode_tag(ErlTermTag::NEW_REFERENCE_EXT));
+            Eterm::Atom(ref atom) =>
+                self._encode_any_atom(atom),
+            Eterm::Reference(ref reference) => {
+                self._encode_tag(if self._big_creation() {ErlTermTag::NEWER_REFERENCE_EXT} else {ErlTermTag::NEW_REFERENCE_EXT})?;
                 self.encode_new_reference(reference)
             },
-            Eterm::Port(port) => {
-                try!(self._encode_tag(ErlTermTag::PORT_EXT));
-                self.encode_port(port)
-            },
-            Eterm::Pid(pid) => {
-                try!(self._encode_tag(ErlTermTag::PID_EXT));
-                self.encode_pid(pid)
+            Eterm::Port(ref port) => match self.options.identifier_tags {
+                IdentifierTags::V4 if port.id > u32::MAX as u64 => {
+                    self._encode_tag(ErlTermTag::V4_PORT_EXT)?;
+                    self.encode_v4_port(port)
+                },
+                IdentifierTags::Legacy => {
+                    self._encode_tag(ErlTermTag::PORT_EXT)?;
+                    self.encode_port(port)
+                },
+                _ => {
+                    self._encode_tag(ErlTermTag::NEW_PORT_EXT)?;
+                    self.encode_port(port)
+                }
             },
-            Eterm::Tuple(tuple) => {
+            Eterm::Pid(ref pid) =>
+                self._encode_any_pid(pid),
+            Eterm::Tuple(ref tuple) => {
                 if tuple.len() <= 255 {
-                    try!(self._encode_tag(ErlTermTag::SMALL_TUPLE_EXT));
+                    self._encode_tag(ErlTermTag::SMALL_TUPLE_EXT)?;
                     self.encode_small_tuple(tuple)
                 } else {
-                    try!(self._encode_tag(ErlTermTag::LARGE_TUPLE_EXT));
+                    self._encode_tag(ErlTermTag::LARGE_TUPLE_EXT)?;
                     self.encode_large_tuple(tuple)
                 }
             },
-            Eterm::Map(map) => {
-                try!(self._encode_tag(ErlTermTag::MAP_EXT));
+            Eterm::Map(ref map) => {
+                self._encode_tag(ErlTermTag::MAP_EXT)?;
                 self.encode_map(map)
             },
             Eterm::Nil =>
                 self._encode_tag(ErlTermTag::NIL_EXT),
-            Eterm::String(s) => {
-                try!(self._encode_tag(ErlTermTag::STRING_EXT));
-                self.encode_string(s)
-            },
-            Eterm::List(list) => {
-                try!(self._encode_tag(ErlTermTag::LIST_EXT));
-                self.encode_list(list)
-            },
-            Eterm::Binary(bin) => {
-                try!(self._encode_tag(ErlTermTag::BINARY_EXT));
+            Eterm::String(ref s) =>
+                self._encode_any_string(s),
+            Eterm::List(ref list) =>
+                self._encode_any_list(list),
+            Eterm::Binary(ref bin) => {
+                self._encode_tag(ErlTermTag::BINARY_EXT)?;
                 self.encode_binary(bin)
             },
-            Eterm::BigNum(num) => {
+            Eterm::BigNum(ref num) => {
+                if let Some(small) = num.to_i32() {
+                    return self._encode_any_integer(small);
+                }
                 let (sign, bytes) = num.to_bytes_le();
-                if bytes.len() < 255 {
-                    try!(self._encode_tag(ErlTermTag::SMALL_BIG_EXT));
-                    self.encode_small_big(sign, bytes)
+                if bytes.len() <= 255 {
+                    self._encode_tag(ErlTermTag::SMALL_BIG_EXT)?;
+                    self.encode_small_big(sign, &bytes)
                 } else {
-                    try!(self._encode_tag(ErlTermTag::LARGE_BIG_EXT));
-                    self.encode_large_big(sign, bytes)
+                    self._encode_tag(ErlTermTag::LARGE_BIG_EXT)?;
+                    self.encode_large_big(sign, &bytes)
                 }
             },
-            Eterm::Fun(fun) => {
-                try!(self._encode_tag(ErlTermTag::FUN_EXT));
+            Eterm::Fun(ref fun) => {
+                self._encode_tag(ErlTermTag::FUN_EXT)?;
                 self.encode_fun(fun)
             },
-            Eterm::NewFun(new_fun) => {
-                try!(self._encode_tag(ErlTermTag::NEW_FUN_EXT));
+            Eterm::NewFun(ref new_fun) => {
+                self._encode_tag(ErlTermTag::NEW_FUN_EXT)?;
                 self.encode_new_fun(new_fun)
             },
-            Eterm::Export(export) => {
-                try!(self._encode_tag(ErlTermTag::EXPORT_EXT));
+            Eterm::Export(ref export) => {
+                self._encode_tag(ErlTermTag::EXPORT_EXT)?;
                 self.encode_export(export)
             },
-            Eterm::BitBinary(bit_binary) => {
-                try!(self._encode_tag(ErlTermTag::BIT_BINARY_EXT));
+            Eterm::BitBinary(ref bit_binary) => {
+                self._encode_tag(ErlTermTag::BIT_BINARY_EXT)?;
                 self.encode_bit_binary(bit_binary)
             }
         }
@@ -839,25 +1726,25 @@ impl<'a> Encoder<'a> {
 
 #[cfg(test)]
 mod test {
-    use super::{Eterm,Encoder,Decoder,DecodeResult,Error};
+    use super::{Atom,AtomTable,Eterm,Encoder,EncoderOptions,Decoder,DecoderOptions,DecodeResult,Error,encoded_size,to_vec,to_iovec};
     use std::io;
     use std::iter::FromIterator;
     use num::bigint;
     use num::traits::FromPrimitive;
 
-    fn term_to_binary(term: Eterm) -> Result<Vec<u8>, Error> {
+    fn term_to_binary(term: &Eterm) -> Result<Vec<u8>, Error> {
         let mut writer = Vec::new();
         {
-            let mut encoder = Encoder::new(&mut writer, false, false, true);
-            try!(encoder.write_prelude());
-            try!(encoder.encode_term(term));
+            let mut encoder = Encoder::new(&mut writer, EncoderOptions::new());
+            encoder.write_prelude()?;
+            encoder.encode_term(term)?;
         }
         Ok(writer)
     }
     fn binary_to_term(binary: Vec<u8>) -> DecodeResult {
         let mut reader = io::Cursor::new(binary);
         let mut decoder = Decoder::new(&mut reader);
-        assert!(true == try!(decoder.read_prelude()));
+        assert!(decoder.read_prelude()?);
         decoder.decode_term()
     }
 
@@ -865,7 +1752,7 @@ mod test {
         ($inp:expr) => {
             {
                 let orig = $inp;
-                let teleported = binary_to_term(term_to_binary(orig.clone()).unwrap()).unwrap();
+                let teleported = binary_to_term(term_to_binary(&orig).unwrap()).unwrap();
                 assert_eq!(orig, teleported);
             }
         };
@@ -886,6 +1773,11 @@ mod test {
         codec_eq!(Eterm::Integer(2147483647));
     }
 
+    #[test]
+    fn codec_integer_min() {
+        codec_eq!(Eterm::Integer(i32::MIN));
+    }
+
     #[test]
     fn codec_float() {
         codec_eq!(Eterm::Float(-111111.11));
@@ -895,14 +1787,14 @@ mod test {
 
     #[test]
     fn codec_atom() {
-        codec_eq!(Eterm::Atom(String::from("hello_world")));
+        codec_eq!(Eterm::Atom(Atom::from("hello_world")));
     }
 
     #[test]
     fn codec_reference() {
-        let node = String::from("my_node");
+        let node = Atom::from("my_node");
         let reference = Eterm::Reference(super::Reference {
-            node: node,
+            node,
             id: vec!(0, 1, 2, 3),
             creation: 0
         });
@@ -912,7 +1804,7 @@ mod test {
     #[test]
     fn codec_port() {
         codec_eq!(Eterm::Port(super::Port {
-            node: String::from("my_node"),
+            node: Atom::from("my_node"),
             id: 4294967295,
             creation: 0
         }));
@@ -921,7 +1813,7 @@ mod test {
     #[test]
     fn codec_pid() {
         codec_eq!(Eterm::Pid(super::Pid {
-            node: String::from("my_node"),
+            node: Atom::from("my_node"),
             id: 4294967295,
             serial: 1,
             creation: 0
@@ -939,11 +1831,25 @@ mod test {
     #[test]
     fn codec_map() {
         // #{0 => {}, 0.0 => -1}
-        let mut map: super::Map = Vec::new();
-        map.push((Eterm::SmallInteger(0), Eterm::Tuple(vec!())));
-        map.push((Eterm::Float(0.0), Eterm::Integer(-1)));
+        let map: super::Map = vec!(
+            (Eterm::SmallInteger(0), Eterm::Tuple(vec!())),
+            (Eterm::Float(0.0), Eterm::Integer(-1))).into();
         let emap = Eterm::Map(map);
-        codec_eq!(emap);
+        codec_eq!(emap.clone());
+        // equality doesn't depend on order of pairs
+        let reversed = Eterm::Map(vec!(
+            (Eterm::Float(0.0), Eterm::Integer(-1)),
+            (Eterm::SmallInteger(0), Eterm::Tuple(vec!()))).into());
+        assert_eq!(emap, reversed);
+        // #{a => 1, a => 2}
+        let dup = [131, 116, 0, 0, 0, 2, 119, 1, 97, 97, 1, 119, 1, 97, 97, 2];
+        match binary_to_term(dup.to_vec()) {
+            Err(Error::DuplicateKey(key)) => assert_eq!(Eterm::Atom(Atom::from("a")), key),
+            other => panic!("{:?}", other),
+        }
+        // decoding doesn't look up each key in map, built so far
+        let big: super::Map = (0..200_000).map(|i| (Eterm::from_i64(i), Eterm::from_i64(-i))).collect();
+        codec_eq!(Eterm::Map(big));
     }
 
     #[test]
@@ -954,7 +1860,7 @@ mod test {
     #[test]
     fn codec_string() {
         // Vec::from_fn(255, |i| i as u8);
-        let vec: Vec<u8> = FromIterator::from_iter(0..(255 as u8));
+        let vec: Vec<u8> = FromIterator::from_iter(0..255u8);
         codec_eq!(Eterm::String(vec));
     }
 
@@ -981,24 +1887,24 @@ mod test {
     fn codec_big_num() {
         codec_eq!(Eterm::BigNum(bigint::BigInt::new(bigint::Sign::Plus, vec!(1, 1, 1, 1, 1, 1))));
         codec_eq!(Eterm::BigNum(bigint::BigInt::new(bigint::Sign::Minus, vec!(1, 1, 1, 1, 1, 1))));
-        codec_eq!(Eterm::BigNum(FromPrimitive::from_i64(i64::max_value()).unwrap()));
-        let vec: Vec<u32> = FromIterator::from_iter(0..(256 as u32));
+        codec_eq!(Eterm::BigNum(FromPrimitive::from_i64(i64::MAX).unwrap()));
+        let vec: Vec<u32> = FromIterator::from_iter(0..256u32);
         codec_eq!(Eterm::BigNum(bigint::BigInt::new(bigint::Sign::Plus, vec)));
     }
 
     #[test]
     fn codec_fun() {
         let pid = super::Pid {
-            node: String::from("my_node"),
+            node: Atom::from("my_node"),
             id: 4294967295,
             serial: 1,
             creation: 0
         };
         codec_eq!(Eterm::Fun(super::Fun {
-            pid: pid,
-            module: String::from("my_mod"),
+            pid,
+            module: Atom::from("my_mod"),
             index: 1,
-            uniq: u32::max_value(),
+            uniq: u32::MAX,
             free_vars: vec!(Eterm::Nil)
         }));
     }
@@ -1006,20 +1912,20 @@ mod test {
     #[test]
     fn codec_new_fun() {
         let pid = super::Pid {
-            node: String::from("my_node"),
-            id: u32::max_value(),
+            node: Atom::from("my_node"),
+            id: u32::MAX,
             serial: 1,
             creation: 0
         };
-        let vec: Vec<u8> = FromIterator::from_iter(0..(16 as u8));
+        let vec: Vec<u8> = FromIterator::from_iter(0..16u8);
         codec_eq!(Eterm::NewFun(super::NewFun {
             arity: 128,         // :-)
             uniq: vec, //Vec::from_fn(16, |i| i as u8),
-            index: u32::max_value(),
-            module: String::from("my_mod"),
-            old_index: u32::max_value(),
-            old_uniq: u32::max_value(),
-            pid: pid,
+            index: u32::MAX,
+            module: Atom::from("my_mod"),
+            old_index: u32::MAX,
+            old_uniq: u32::MAX,
+            pid,
             free_vars: vec!(Eterm::Nil)
         }));
     }
@@ -1027,9 +1933,9 @@ mod test {
     #[test]
     fn codec_export() {
         codec_eq!(Eterm::Export(super::Export {
-            module: String::from("my_mod"),
-            function: String::from("my_fun"),
-            arity: u8::max_value()
+            module: Atom::from("my_mod"),
+            function: Atom::from("my_fun"),
+            arity: u8::MAX
         }));
     }
 
@@ -1040,4 +1946,528 @@ mod test {
             data: vec!(255, 255)
         }));
     }
+
+    #[test]
+    fn encode_by_reference() {
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Binary(vec!(1, 2, 3))));
+        let by_ref = term_to_binary(&term).unwrap();
+        let mut by_value = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut by_value, EncoderOptions::new());
+            encoder.write_prelude().unwrap();
+            encoder.encode_term(term).unwrap();
+        }
+        assert_eq!(by_ref, by_value);
+    }
+
+    #[test]
+    fn encode_size() {
+        let pid = super::Pid {
+            node: Atom::from("my_node"),
+            id: 1,
+            serial: 1,
+            creation: 0
+        };
+        let term = Eterm::Tuple(vec!(
+            Eterm::Atom(Atom::from("ok")),
+            Eterm::BigNum(FromPrimitive::from_i64(i64::MAX).unwrap()),
+            Eterm::NewFun(super::NewFun {
+                arity: 1,
+                uniq: vec!(0; 16),
+                index: 1,
+                module: Atom::from("my_mod"),
+                old_index: 1,
+                old_uniq: 1,
+                pid,
+                free_vars: vec!(Eterm::Binary(vec!(1, 2, 3)))
+            })));
+        let options = EncoderOptions::default();
+        let size = encoded_size(&term, &options).unwrap();
+        let mut buf = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut buf, options);
+            assert_eq!(1, encoder.write_prelude().unwrap());
+            assert_eq!(size, encoder.encode_term(&term).unwrap());
+            assert_eq!(size + 1, encoder.bytes_written());
+        }
+        assert_eq!(size + 1, buf.len());
+    }
+
+    fn encode_with(term: &Eterm, options: EncoderOptions) -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut buf, options);
+            encoder.write_prelude().unwrap();
+            encoder.encode_term(term).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn encode_otp_presets() {
+        let ok = Eterm::Atom(Atom::from("ok"));
+        assert_eq!(vec!(131, 100, 0, 2, 111, 107), encode_with(&ok, EncoderOptions::otp(17)));
+        assert_eq!(vec!(131, 119, 2, 111, 107), encode_with(&ok, EncoderOptions::otp(26)));
+        assert_eq!(encode_with(&ok, EncoderOptions::otp(26)),
+                   encode_with(&ok, EncoderOptions::new().minor_version(2)));
+
+        let pid = Eterm::Pid(super::Pid {
+            node: Atom::from("nonode@nohost"),
+            id: 80,
+            serial: 0,
+            creation: 1700000000
+        });
+        let bin = encode_with(&pid, EncoderOptions::otp(23));
+        assert_eq!(88, bin[1]);
+        assert_eq!(pid, binary_to_term(bin).unwrap());
+        assert!(Encoder::new(&mut io::sink(), EncoderOptions::otp(22)).encode_term(&pid).is_err());
+
+        let port = Eterm::Port(super::Port {
+            node: Atom::from("nonode@nohost"),
+            id: 1 << 40,
+            creation: 1700000000
+        });
+        let bin = encode_with(&port, EncoderOptions::otp(24));
+        assert_eq!(120, bin[1]);
+        assert_eq!(port, binary_to_term(bin).unwrap());
+
+        let reference = Eterm::Reference(super::Reference {
+            node: Atom::from("nonode@nohost"),
+            id: vec!(0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3),
+            creation: 1700000000
+        });
+        let bin = encode_with(&reference, EncoderOptions::otp(25));
+        assert_eq!(90, bin[1]);
+        assert_eq!(reference, binary_to_term(bin).unwrap());
+    }
+
+    #[test]
+    fn encode_otp_golden() {
+        // term_to_binary(1.5) of OTP 17 and 26; FLOAT_EXT of minor_version 0
+        let float = Eterm::Float(1.5);
+        for release in [17, 26] {
+            assert_eq!(vec!(131, 70, 63, 248, 0, 0, 0, 0, 0, 0), encode_with(&float, EncoderOptions::otp(release)));
+        }
+        let mut old = vec!(131, 99);
+        old.extend(b"1.50000000000000000000e+00\0\0\0\0\0");
+        assert_eq!(old, encode_with(&float, EncoderOptions::otp(17).minor_version(0)));
+
+        // term_to_binary(self()) of `erl` shell, pid <0.80.0>
+        let pid = Eterm::Pid(super::Pid {
+            node: Atom::from("nonode@nohost"),
+            id: 80,
+            serial: 0,
+            creation: 0
+        });
+        let mut otp17 = vec!(131, 103, 100, 0, 13);
+        otp17.extend(b"nonode@nohost");
+        otp17.extend(&[0, 0, 0, 80, 0, 0, 0, 0, 0]);
+        assert_eq!(otp17, encode_with(&pid, EncoderOptions::otp(17)));
+        let mut otp26 = vec!(131, 88, 119, 13);
+        otp26.extend(b"nonode@nohost");
+        otp26.extend(&[0, 0, 0, 80, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(otp26, encode_with(&pid, EncoderOptions::otp(26)));
+
+        // NEW_FUN_EXT, laid out as erl_ext_dist describes; Size includes itself
+        let fun = Eterm::NewFun(super::NewFun {
+            arity: 1,
+            uniq: vec!(7; 16),
+            index: 2,
+            module: Atom::from("m"),
+            old_index: 3,
+            old_uniq: 4,
+            pid: super::Pid{node: Atom::from("n"), id: 1, serial: 2, creation: 0},
+            free_vars: vec!()
+        });
+        let fun_bin = |size: u8, module: &[u8], pid: &[u8]| {
+            let mut bin = vec!(131, 112, 0, 0, 0, size, 1);
+            bin.extend(&[7; 16]);
+            bin.extend(&[0, 0, 0, 2, 0, 0, 0, 0]);
+            bin.extend(module);
+            bin.extend(&[97, 3, 97, 4]);
+            bin.extend(pid);
+            bin
+        };
+        assert_eq!(fun_bin(51, &[100, 0, 1, b'm'], &[103, 100, 0, 1, b'n', 0, 0, 0, 1, 0, 0, 0, 2, 0]),
+                   to_vec(&fun, &EncoderOptions::otp(17).fair_new_fun(true)).unwrap());
+        assert_eq!(fun_bin(52, &[119, 1, b'm'], &[88, 119, 1, b'n', 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0]),
+                   to_vec(&fun, &EncoderOptions::otp(26).fair_new_fun(true)).unwrap());
+
+        // term_to_binary(#{<<"a">> => 3, 1 => 2}): small maps are written in
+        // key order, which `otp` doesn't do by itself
+        let map = Eterm::Map(vec!((Eterm::Binary(b"a".to_vec()), Eterm::SmallInteger(3)),
+                                  (Eterm::SmallInteger(1), Eterm::SmallInteger(2))).into());
+        let sorted = vec!(131, 116, 0, 0, 0, 2, 97, 1, 97, 2, 109, 0, 0, 0, 1, b'a', 97, 3);
+        for release in [17, 26] {
+            assert_eq!(sorted, encode_with(&map, EncoderOptions::otp(release).deterministic()));
+            assert!(sorted != encode_with(&map, EncoderOptions::otp(release)));
+        }
+    }
+
+    #[test]
+    fn encode_atom_latin1() {
+        let atom = Eterm::Atom(Atom::from("h\u{e5}ll"));
+        let bin = encode_with(&atom, EncoderOptions::new());
+        assert_eq!(vec!(131, 100, 0, 4, b'h', 0xe5, b'l', b'l'), bin);
+        assert_eq!(atom, binary_to_term(bin).unwrap());
+        // doesn't fit into latin1
+        let atom = Eterm::Atom(Atom::from("\u{43f}\u{440}"));
+        let bin = encode_with(&atom, EncoderOptions::new());
+        assert_eq!(vec!(131, 119, 4, 0xd0, 0xbf, 0xd1, 0x80), bin);
+        assert_eq!(atom, binary_to_term(bin).unwrap());
+    }
+
+    #[test]
+    fn encode_old_float() {
+        let float = Eterm::Float(-0.1);
+        let bin = encode_with(&float, EncoderOptions::new().minor_version(0));
+        assert_eq!(2 + 31, bin.len());
+        assert_eq!(99, bin[1]);
+        assert_eq!(b"-1.00000000000000005551e-01\0\0\0\0".to_vec(), bin[2..].to_vec());
+        assert_eq!(float, binary_to_term(bin).unwrap());
+        codec_eq!(Eterm::Float(1.0e300));
+    }
+
+    #[test]
+    fn encode_compressed() {
+        let options = EncoderOptions::new().compressed(6);
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Binary(vec!(0; 1000))));
+        let bin = encode_with(&term, options.clone());
+        assert_eq!(80, bin[1]);
+        assert!(bin.len() < 100);
+        assert_eq!(bin.len() - 1, encoded_size(&term, &options).unwrap());
+        assert_eq!(term, binary_to_term(bin).unwrap());
+        // COMPRESSED tag, uncompressed size, zlib stream
+        let term = Eterm::Binary(vec!(0; 1000));
+        let bin = encode_with(&term, options.clone());
+        assert_eq!(vec!(131, 80, 0, 0, 3, 237, 0x78), bin[..7].to_vec());
+        // level is clamped
+        assert_eq!(encode_with(&term, EncoderOptions::new().compressed(9)),
+                   encode_with(&term, EncoderOptions::new().compressed(100)));
+        // not worth compressing
+        let term = Eterm::Atom(Atom::from("ok"));
+        assert_eq!(encode_with(&term, EncoderOptions::new()), encode_with(&term, options));
+    }
+
+    #[test]
+    fn encode_errors() {
+        let encode = |term: Eterm| Encoder::new(&mut io::sink(), EncoderOptions::new()).encode_term(term);
+        match encode(Eterm::Atom(Atom::from("a".repeat(256)))) {
+            Err(Error::AtomTooLong(256)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match encode(Eterm::Float(f64::NAN)) {
+            Err(Error::NonFiniteFloat(_)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match encode(Eterm::List(vec!())) {
+            Err(Error::EmptyList) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match encode(Eterm::Reference(super::Reference {
+            node: Atom::from("my_node"),
+            id: vec!(0, 1, 2),
+            creation: 0
+        })) {
+            Err(Error::BadReferenceId(3)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match encode(Eterm::Pid(super::Pid {
+            node: Atom::from("my_node"),
+            id: 1,
+            serial: 1,
+            creation: 256
+        })) {
+            Err(Error::CreationTooLarge(256)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match encode(Eterm::NewFun(super::NewFun {
+            arity: 0,
+            uniq: vec!(0; 15),
+            index: 1,
+            module: Atom::from("my_mod"),
+            old_index: 1,
+            old_uniq: 1,
+            pid: super::Pid {
+                node: Atom::from("my_node"),
+                id: 1,
+                serial: 1,
+                creation: 0
+            },
+            free_vars: vec!()
+        })) {
+            Err(Error::BadFunUniq(15)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        // zero is a valid bignum
+        assert_eq!(vec!(131, 97, 0), encode_with(&Eterm::BigNum(FromPrimitive::from_i64(0).unwrap()),
+                                                 EncoderOptions::new()));
+    }
+
+    #[test]
+    fn encode_deterministic() {
+        let atom = |name: &str| Eterm::Atom(Atom::from(name));
+        let inner = |a, b| Eterm::Map(vec!((atom("y"), a), (atom("x"), b)).into());
+        let one = Eterm::Map(vec!(
+            (atom("b"), inner(Eterm::Nil, Eterm::SmallInteger(1))),
+            (Eterm::Float(1.0), atom("float")),
+            (atom("a"), Eterm::Nil),
+            (Eterm::SmallInteger(2), atom("int"))).into());
+        let other = Eterm::Map(vec!(
+            (Eterm::SmallInteger(2), atom("int")),
+            (atom("a"), Eterm::Nil),
+            (Eterm::Float(1.0), atom("float")),
+            (atom("b"), Eterm::Map(vec!((atom("x"), Eterm::SmallInteger(1)), (atom("y"), Eterm::Nil)).into()))).into());
+        let options = EncoderOptions::new().deterministic();
+        let bin = encode_with(&one, options.clone());
+        assert_eq!(bin, encode_with(&other, options));
+        assert!(bin != encode_with(&one, EncoderOptions::new()));
+        // integers go before floats, then atoms
+        let sorted = Eterm::Map(vec!(
+            (Eterm::SmallInteger(2), atom("int")),
+            (Eterm::Float(1.0), atom("float")),
+            (atom("a"), Eterm::Nil),
+            (atom("b"), Eterm::Map(vec!((atom("x"), Eterm::SmallInteger(1)), (atom("y"), Eterm::Nil)).into()))).into());
+        assert_eq!(sorted, binary_to_term(bin).unwrap());
+    }
+
+    #[test]
+    fn encode_canonical_integers() {
+        let big = |num: i64| Eterm::BigNum(FromPrimitive::from_i64(num).unwrap());
+        assert_eq!(vec!(131, 97, 5), encode_with(&big(5), EncoderOptions::new()));
+        assert_eq!(vec!(131, 97, 5), encode_with(&Eterm::Integer(5), EncoderOptions::new()));
+        assert_eq!(vec!(131, 98, 255, 255, 255, 255), encode_with(&big(-1), EncoderOptions::new()));
+        assert_eq!(vec!(131, 110, 4, 0, 0, 0, 0, 128), encode_with(&big(1 << 31), EncoderOptions::new()));
+        // 255 bytes is still SMALL_BIG_EXT
+        let num: bigint::BigInt = (bigint::BigInt::from(1) << (255 * 8)) - 1;
+        assert_eq!(110, encode_with(&Eterm::BigNum(num.clone()), EncoderOptions::new())[1]);
+        assert_eq!(111, encode_with(&Eterm::BigNum(num << 8), EncoderOptions::new())[1]);
+
+        assert_eq!(Eterm::SmallInteger(255), Eterm::from_i64(255));
+        assert_eq!(Eterm::Integer(-1), Eterm::from_i64(-1));
+        assert_eq!(Eterm::Integer(256), Eterm::from_u64(256));
+        assert_eq!(big(i64::MIN), Eterm::from_i64(i64::MIN));
+        assert_eq!(Eterm::BigNum(bigint::BigInt::from(u128::MAX)), Eterm::from_u128(u128::MAX));
+        assert_eq!(Eterm::SmallInteger(7), Eterm::from_i128(7));
+        codec_eq!(Eterm::from_u64(u64::MAX));
+        codec_eq!(Eterm::from_i128(i128::MIN));
+    }
+
+    #[test]
+    fn integer_accessors() {
+        let big = |num: i64| Eterm::BigNum(FromPrimitive::from_i64(num).unwrap());
+        // equal in any representation
+        assert_eq!(Eterm::SmallInteger(1), Eterm::Integer(1));
+        assert_eq!(Eterm::Integer(1), big(1));
+        assert!(Eterm::SmallInteger(1) != Eterm::Float(1.0));
+        for term in [Eterm::SmallInteger(7), Eterm::Integer(7), big(7)].iter() {
+            assert_eq!(Some(7), term.as_i64());
+            assert_eq!(Some(7), term.as_u64());
+            assert_eq!(Some(bigint::BigInt::from(7)), term.as_bigint());
+            assert_eq!(Some(7.0), term.as_f64());
+        }
+        assert_eq!(None, Eterm::Integer(-1).as_u64());
+        assert_eq!(Some(u64::MAX), Eterm::from_u64(u64::MAX).as_u64());
+        assert_eq!(None, Eterm::from_u64(u64::MAX).as_i64());
+        assert_eq!(Some(2.5), Eterm::Float(2.5).as_f64());
+        assert_eq!(None, Eterm::Float(2.0).as_i64());
+        assert_eq!(None, Eterm::Nil.as_bigint());
+        assert_eq!(None, Eterm::BigNum(bigint::BigInt::from(1) << 1100).as_f64());
+
+        let term = Eterm::Tuple(vec!(big(5), Eterm::Integer(-1), big(1 << 40),
+                                     Eterm::Map(vec!((Eterm::Integer(1), big(300))).into()),
+                                     Eterm::List(vec!(Eterm::Integer(255), Eterm::Nil))));
+        match term.normalize() {
+            Eterm::Tuple(elems) => {
+                assert!(matches!(elems[0], Eterm::SmallInteger(5)));
+                assert!(matches!(elems[1], Eterm::Integer(-1)));
+                assert!(matches!(elems[2], Eterm::BigNum(_)));
+                match elems[3] {
+                    Eterm::Map(ref map) => {
+                        let (key, val) = map.iter().next().unwrap();
+                        assert!(matches!(*key, Eterm::SmallInteger(1)));
+                        assert!(matches!(*val, Eterm::Integer(300)));
+                    },
+                    ref other => panic!("{:?}", other),
+                }
+                assert!(matches!(elems[4], Eterm::List(ref list) if matches!(list[0], Eterm::SmallInteger(255))));
+            },
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn encode_string_choice() {
+        let bytes = |n: usize| Eterm::List((0..n).map(|i| Eterm::SmallInteger(i as u8))
+                                           .chain(Some(Eterm::Nil)).collect());
+        let bin = encode_with(&bytes(3), EncoderOptions::new());
+        assert_eq!(vec!(131, 107, 0, 3, 0, 1, 2), bin);
+        assert_eq!(Eterm::String(vec!(0, 1, 2)), binary_to_term(bin).unwrap());
+        assert_eq!(vec!(131, 107, 0, 1, 200),
+                   encode_with(&Eterm::List(vec!(Eterm::Integer(200), Eterm::Nil)), EncoderOptions::new()));
+        assert_eq!(vec!(131, 106), encode_with(&Eterm::String(vec!()), EncoderOptions::new()));
+        assert_eq!(vec!(131, 106), encode_with(&Eterm::List(vec!(Eterm::Nil)), EncoderOptions::new()));
+        // improper list and non-bytes
+        assert_eq!(108, encode_with(&Eterm::List(vec!(Eterm::SmallInteger(1), Eterm::SmallInteger(2))),
+                                    EncoderOptions::new())[1]);
+        assert_eq!(108, encode_with(&Eterm::List(vec!(Eterm::Integer(256), Eterm::Nil)),
+                                    EncoderOptions::new())[1]);
+        // too long for STRING_EXT
+        let long = Eterm::String(vec!(7; 65536));
+        let bin = encode_with(&long, EncoderOptions::new());
+        assert_eq!(108, bin[1]);
+        assert_eq!(encode_with(&bytes(65536), EncoderOptions::new())[..6], bin[..6]);
+        assert_eq!(107, encode_with(&bytes(65535), EncoderOptions::new())[1]);
+
+        let mut reader = io::Cursor::new(vec!(131, 107, 0, 2, 1, 2));
+        let mut decoder = Decoder::with_options(&mut reader, DecoderOptions::new().strings_as_lists(true));
+        assert!(decoder.read_prelude().unwrap());
+        assert_eq!(Eterm::List(vec!(Eterm::SmallInteger(1), Eterm::SmallInteger(2), Eterm::Nil)),
+                   decoder.decode_term().unwrap());
+    }
+
+    #[test]
+    fn encode_to_vec() {
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Binary(vec!(1; 100))));
+        let bin = to_vec(&term, &EncoderOptions::new()).unwrap();
+        assert_eq!(bin.len(), bin.capacity());
+        assert_eq!(encode_with(&term, EncoderOptions::new()), bin);
+        let options = EncoderOptions::new().compressed(9);
+        assert_eq!(term, binary_to_term(to_vec(&term, &options).unwrap()).unwrap());
+
+        let mut encoder = Encoder::new(Vec::new(), EncoderOptions::new());
+        encoder.write_prelude().unwrap();
+        encoder.encode_term(&term).unwrap();
+        assert_eq!(bin, encoder.into_inner());
+    }
+
+    #[test]
+    fn encode_new_fun_size() {
+        let fun = |free_vars| Eterm::NewFun(super::NewFun {
+            arity: 1,
+            uniq: vec!(0; 16),
+            index: 2,
+            module: Atom::from("my_mod"),
+            old_index: 3,
+            old_uniq: 4,
+            pid: super::Pid{node: Atom::from("my_node"), id: 1, serial: 2, creation: 3},
+            free_vars
+        });
+        let term = fun(vec!(fun(vec!(Eterm::Nil)), Eterm::SmallInteger(1)));
+        let options = EncoderOptions::new().fair_new_fun(true);
+        // temporary buffer
+        let buffered = encode_with(&term, options.clone());
+        assert_eq!((buffered.len() - 2) as u32,
+                   u32::from_be_bytes([buffered[2], buffered[3], buffered[4], buffered[5]]));
+        // back-patched
+        assert_eq!(buffered, to_vec(&term, &options).unwrap());
+        let mut encoder = Encoder::with_seek(io::Cursor::new(vec!(0xFF)), options.clone());
+        encoder.write_prelude().unwrap();
+        encoder.encode_term(&term).unwrap();
+        assert_eq!(buffered, encoder.into_inner().into_inner());
+        assert_eq!(buffered.len() - 1, encoded_size(&term, &options).unwrap());
+        assert_eq!(term, binary_to_term(buffered).unwrap());
+    }
+
+    #[test]
+    fn encode_iovec() {
+        let big = vec!(7; 1 << 20);
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("data")),
+                                     Eterm::Binary(big.clone()),
+                                     Eterm::Binary(vec!(1, 2, 3)),
+                                     Eterm::Map(vec!((Eterm::Binary(vec!(8; 64)), Eterm::Nil)).into())));
+        let options = EncoderOptions::new().deterministic();
+        let iovec = to_iovec(&term, &options).unwrap();
+        let bin = to_vec(&term, &options).unwrap();
+        assert_eq!(bin.len(), iovec.len());
+        assert_eq!(bin, iovec.to_vec());
+        let slices = iovec.slices();
+        assert_eq!(5, slices.len());
+        // payload isn't copied
+        match term {
+            Eterm::Tuple(ref elems) => match elems[1] {
+                Eterm::Binary(ref data) => assert_eq!(data.as_ptr(), slices[1].as_ptr()),
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+        let mut written = Vec::new();
+        iovec.write_to(&mut written).unwrap();
+        assert_eq!(bin, written);
+
+        let options = EncoderOptions::new().compressed(6);
+        let iovec = to_iovec(&term, &options).unwrap();
+        assert_eq!(1, iovec.slices().len());
+        assert_eq!(term, binary_to_term(iovec.to_vec()).unwrap());
+        assert!(!to_iovec(&Eterm::Nil, &options).unwrap().is_empty());
+    }
+
+    #[test]
+    fn struct_constructors() {
+        let pid = super::Pid::new("node@host", 1, 2, 3).unwrap();
+        assert_eq!(("node@host", 1, 2, 3), (pid.node(), pid.id(), pid.serial(), pid.creation()));
+        let reference = super::Reference::new("node@host", &[1, 2, u32::MAX], 4).unwrap();
+        assert_eq!(vec!(1, 2, u32::MAX), reference.id());
+        codec_eq!(Eterm::Reference(reference));
+        let port = super::Port::new("node@host", u32::MAX as u64, 5).unwrap();
+        assert_eq!((u32::MAX as u64, 5), (port.id(), port.creation()));
+        let fun = super::NewFun::new(pid.clone(), "my_mod", 1, 2, vec!(0; 16)).unwrap()
+            .with_old_index(3)
+            .with_old_uniq(4)
+            .with_free_vars(vec!(Eterm::SmallInteger(5)));
+        assert_eq!((1, "my_mod", 3, 4), (fun.arity(), fun.module(), fun.old_index(), fun.old_uniq()));
+        assert_eq!(&[Eterm::SmallInteger(5)], fun.free_vars());
+        codec_eq!(Eterm::NewFun(fun));
+        let fun = super::Fun::new(pid, "my_mod", 1, 2).unwrap().with_free_vars(vec!(Eterm::Nil));
+        codec_eq!(Eterm::Fun(fun));
+        let export = super::Export::new("lists", "map", 2).unwrap();
+        assert_eq!(("lists", "map", 2), (export.module(), export.function(), export.arity()));
+        let bit_bin = super::BitBinary::new(vec!(1, 0x80), 1).unwrap();
+        assert_eq!(9, bit_bin.bit_len());
+        codec_eq!(Eterm::BitBinary(bit_bin));
+
+        match super::Reference::new("node@host", &[], 0) {
+            Err(Error::BadReferenceId(0)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match super::NewFun::new(super::Pid::new("n", 1, 2, 3).unwrap(), "m", 0, 0, vec!(0; 15)) {
+            Err(Error::BadFunUniq(15)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match super::BitBinary::new(vec!(1), 0) {
+            Err(Error::BadBitBinary(0, 1)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        match super::Export::new("m", "f".repeat(256), 0) {
+            Err(Error::AtomTooLong(256)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn decode_atom_table() {
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Atom(Atom::from("error"))));
+        let bin = to_vec(&term, &EncoderOptions::new()).unwrap();
+        let table = AtomTable::new();
+        let options = DecoderOptions::new().atom_table(Some(table.clone()));
+        let mut reader = io::BufReader::new(&bin[..]);
+        let mut decoder = Decoder::with_options(&mut reader, options);
+        assert!(decoder.read_prelude().unwrap());
+        assert_eq!(term, decoder.decode_term().unwrap());
+        assert_eq!(2, table.len());
+
+        let options = DecoderOptions::new().atom_table(Some(AtomTable::with_limit(1)));
+        let mut reader = io::BufReader::new(&bin[..]);
+        let mut decoder = Decoder::with_options(&mut reader, options);
+        assert!(decoder.read_prelude().unwrap());
+        match decoder.decode_term() {
+            Err(Error::AtomTableFull(1)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
 }
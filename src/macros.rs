@@ -0,0 +1,294 @@
+// `eterm!` builds terms from Erlang-like syntax, checked at compile time:
+//
+//     eterm!({error, not_binary})
+//     eterm!(#{<<"id">> => (id), list => [1, 2.5, "str" | tail], neg => -1})
+//
+// * `ok` is an atom, `"str"` is a string, `1` / `2.5` / `'c'` are numbers
+//   (`'c'` is Erlang's `$c`), `true` / `false` are atoms;
+// * `{..}`, `[..]`, `[H | T]`, `#{K => V}` are tuples, lists and maps;
+// * `<<1, "abc", (bytes)>>` is a binary of bytes, strings and `AsRef<[u8]>`
+//   expressions;
+// * `(expr)` is any expression, which is `Into<Eterm>`;
+// * `-1` is a negative number, in binaries too: `<<-1>>` is `<<255>>`.
+//
+// Trailing commas are compile errors, like in Erlang. Binaries should be
+// separated from `=>` by whitespace (`>>=` is a Rust token). Each term is a
+// step of macro recursion, so very long literals may need larger
+// `recursion_limit`.
+
+use super::{Eterm, Atom};
+use super::parse::string_term;
+
+
+// Literals, accepted by `eterm!`
+pub trait Literal {
+    fn into_eterm(self) -> Eterm;
+}
+
+macro_rules! int_literal {
+    ($($t:ty),*) => {
+        $(impl Literal for $t {
+            fn into_eterm(self) -> Eterm {
+                Eterm::from_i128(self as i128)
+            }
+        })*
+    }
+}
+int_literal!(i8, i16, i32, i64, i128, u8, u16, u32, u64, isize, usize);
+
+impl Literal for u128 {
+    fn into_eterm(self) -> Eterm {
+        Eterm::from_u128(self)
+    }
+}
+
+impl Literal for f64 {
+    fn into_eterm(self) -> Eterm {
+        Eterm::Float(self)
+    }
+}
+
+impl Literal for f32 {
+    fn into_eterm(self) -> Eterm {
+        Eterm::Float(self as f64)
+    }
+}
+
+impl Literal for char {
+    fn into_eterm(self) -> Eterm {
+        Eterm::from_i64(self as i64)
+    }
+}
+
+impl Literal for bool {
+    fn into_eterm(self) -> Eterm {
+        Eterm::Atom(Atom::from(if self { "true" } else { "false" }))
+    }
+}
+
+impl Literal for &str {
+    fn into_eterm(self) -> Eterm {
+        string_term(self)
+    }
+}
+
+// Segments of `eterm!` binaries
+pub trait Segment {
+    fn push_to(self, buf: &mut Vec<u8>);
+}
+
+// Integers are truncated to a byte, like `<<256>>` is `<<0>>`
+macro_rules! int_segment {
+    ($($t:ty),*) => {
+        $(impl Segment for $t {
+            fn push_to(self, buf: &mut Vec<u8>) {
+                buf.push(self as u8);
+            }
+        })*
+    }
+}
+int_segment!(i8, i16, i32, i64, u8, u16, u32, u64);
+
+impl Segment for &str {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Segment for &[u8] {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl<const N: usize> Segment for &[u8; N] {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Segment for &Vec<u8> {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self);
+    }
+}
+
+impl Segment for Vec<u8> {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend(self);
+    }
+}
+
+impl Segment for &String {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl Segment for String {
+    fn push_to(self, buf: &mut Vec<u8>) {
+        buf.extend(self.into_bytes());
+    }
+}
+
+#[macro_export]
+macro_rules! eterm {
+    // comma-separated terms of tuple or list; `<<..>>` is collected as a
+    // whole, since it isn't a token tree
+    (@seq $kind:ident [$($done:tt)*] [$($cur:tt)*] << $($rest:tt)*) => {
+        $crate::eterm!(@seq_bin $kind [$($done)*] [$($cur)* <<] $($rest)*)
+    };
+    (@seq_bin $kind:ident [$($done:tt)*] [$($cur:tt)*] >> $($rest:tt)*) => {
+        $crate::eterm!(@seq $kind [$($done)*] [$($cur)* >>] $($rest)*)
+    };
+    (@seq_bin $kind:ident [$($done:tt)*] [$($cur:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::eterm!(@seq_bin $kind [$($done)*] [$($cur)* $next] $($rest)*)
+    };
+    (@seq $kind:ident $done:tt [$($cur:tt)+] ,) => {
+        compile_error!("trailing comma in eterm!")
+    };
+    (@seq $kind:ident [$($done:tt)*] [$($cur:tt)+] , $($rest:tt)+) => {
+        $crate::eterm!(@seq $kind [$($done)* $crate::eterm!($($cur)+),] [] $($rest)*)
+    };
+    (@seq list [$($done:tt)*] [$($cur:tt)+] | $($tail:tt)+) => {
+        $crate::Eterm::List(vec![$($done)* $crate::eterm!($($cur)+), $crate::eterm!($($tail)+)])
+    };
+    (@seq $kind:ident [$($done:tt)*] [$($cur:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::eterm!(@seq $kind [$($done)*] [$($cur)* $next] $($rest)*)
+    };
+    (@seq tuple [$($done:tt)*] [$($cur:tt)+]) => {
+        $crate::Eterm::Tuple(vec![$($done)* $crate::eterm!($($cur)+)])
+    };
+    (@seq tuple [$($done:tt)*] []) => {
+        $crate::Eterm::Tuple(vec![$($done)*])
+    };
+    (@seq list [$($done:tt)*] [$($cur:tt)+]) => {
+        $crate::Eterm::List(vec![$($done)* $crate::eterm!($($cur)+), $crate::Eterm::Nil])
+    };
+    (@seq list [$($done:tt)*] []) => {
+        $crate::Eterm::List(vec![$($done)* $crate::Eterm::Nil])
+    };
+
+    // `key => value` pairs of map
+    (@map [$($done:tt)*] [$($key:tt)+] => $($rest:tt)*) => {
+        $crate::eterm!(@map_val [$($done)*] [$($key)+] [] $($rest)*)
+    };
+    (@map [$($done:tt)*] [$($key:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::eterm!(@map [$($done)*] [$($key)* $next] $($rest)*)
+    };
+    (@map [$($done:tt)*] []) => {
+        $crate::Eterm::Map($crate::ErlMap::from(vec![$($done)*]))
+    };
+    (@map_val $done:tt $key:tt [$($val:tt)*] << $($rest:tt)*) => {
+        $crate::eterm!(@map_bin $done $key [$($val)* <<] $($rest)*)
+    };
+    (@map_bin $done:tt $key:tt [$($val:tt)*] >> $($rest:tt)*) => {
+        $crate::eterm!(@map_val $done $key [$($val)* >>] $($rest)*)
+    };
+    (@map_bin $done:tt $key:tt [$($val:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::eterm!(@map_bin $done $key [$($val)* $next] $($rest)*)
+    };
+    (@map_val $done:tt $key:tt [$($val:tt)+] ,) => {
+        compile_error!("trailing comma in eterm!")
+    };
+    (@map_val [$($done:tt)*] [$($key:tt)+] [$($val:tt)+] , $($rest:tt)+) => {
+        $crate::eterm!(@map [$($done)* ($crate::eterm!($($key)+), $crate::eterm!($($val)+)),] [] $($rest)*)
+    };
+    (@map_val $done:tt $key:tt [$($val:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::eterm!(@map_val $done $key [$($val)* $next] $($rest)*)
+    };
+    (@map_val [$($done:tt)*] [$($key:tt)+] [$($val:tt)+]) => {
+        $crate::Eterm::Map($crate::ErlMap::from(vec![$($done)* ($crate::eterm!($($key)+), $crate::eterm!($($val)+))]))
+    };
+
+    // binary segments
+    (@bin [] >>) => {
+        $crate::Eterm::Binary(Vec::new())
+    };
+    (@bin [$($seg:tt)+] >>) => {{
+        let mut buf = Vec::new();
+        $($crate::macros::Segment::push_to($seg, &mut buf);)*
+        $crate::Eterm::Binary(buf)
+    }};
+    (@bin $segs:tt $next:tt , >>) => {
+        compile_error!("trailing comma in eterm!")
+    };
+    (@bin $segs:tt - $next:tt , >>) => {
+        compile_error!("trailing comma in eterm!")
+    };
+    (@bin [$($seg:tt)*] - $next:tt , $($rest:tt)*) => {
+        $crate::eterm!(@bin [$($seg)* (-$next)] $($rest)*)
+    };
+    (@bin [$($seg:tt)*] - $next:tt >>) => {
+        $crate::eterm!(@bin [$($seg)* (-$next)] >>)
+    };
+    (@bin [$($seg:tt)*] $next:tt , $($rest:tt)*) => {
+        $crate::eterm!(@bin [$($seg)* $next] $($rest)*)
+    };
+    (@bin [$($seg:tt)*] $next:tt >>) => {
+        $crate::eterm!(@bin [$($seg)* $next] >>)
+    };
+
+    (# {$($body:tt)*}) => {
+        $crate::eterm!(@map [] [] $($body)*)
+    };
+    ({$($body:tt)*}) => {
+        $crate::eterm!(@seq tuple [] [] $($body)*)
+    };
+    ([]) => {
+        $crate::Eterm::Nil
+    };
+    ([$($body:tt)+]) => {
+        $crate::eterm!(@seq list [] [] $($body)+)
+    };
+    (<< $($body:tt)*) => {
+        $crate::eterm!(@bin [] $($body)*)
+    };
+    (- $lit:literal) => {
+        $crate::macros::Literal::into_eterm(-$lit)
+    };
+    (($e:expr)) => {
+        ::std::convert::Into::<$crate::Eterm>::into($e)
+    };
+    ($atom:ident) => {
+        $crate::Eterm::Atom($crate::Atom::from(stringify!($atom)))
+    };
+    ($lit:literal) => {
+        $crate::macros::Literal::into_eterm($lit)
+    };
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::{Atom, Eterm};
+
+    fn atom(name: &str) -> Eterm {
+        Eterm::Atom(Atom::from(name))
+    }
+
+    #[test]
+    fn macro_terms() {
+        assert_eq!(Eterm::Tuple(vec!(atom("error"), atom("not_binary"))),
+                   eterm!({error, not_binary}));
+        let id = Eterm::Binary(b"42".to_vec());
+        let tail = atom("tail");
+        assert_eq!(Eterm::Map(vec!((Eterm::Binary(b"id".to_vec()), id.clone()),
+                                   (atom("list"), Eterm::List(vec!(Eterm::SmallInteger(1), Eterm::Float(2.5),
+                                                                   Eterm::String(b"str".to_vec()), atom("tail")))),
+                                   (atom("neg"), Eterm::Integer(-1))).into()),
+                   eterm!(#{<<"id">> => (id), list => [1, 2.5, "str" | (tail)], neg => -1}));
+        assert_eq!(Eterm::Nil, eterm!([]));
+        assert_eq!(Eterm::Tuple(vec!()), eterm!({}));
+        assert_eq!(Eterm::Map(vec!().into()), eterm!(#{}));
+        assert_eq!(Eterm::List(vec!(Eterm::Binary(vec!(1, 2)), Eterm::Binary(vec!()), Eterm::Nil)),
+                   eterm!([<<1, 2>>, <<>>]));
+        let bytes = vec!(3u8, 4);
+        assert_eq!(Eterm::Binary(vec!(1, b'a', 3, 4, 0)), eterm!(<<1, "a", (&bytes), 256>>));
+        assert_eq!(Eterm::Binary(vec!(255, 1, 254)), eterm!(<<-1, 1, -2>>));
+        assert_eq!(Eterm::Tuple(vec!(atom("true"), Eterm::SmallInteger(97), Eterm::from_i64(1 << 40),
+                                     Eterm::Tuple(vec!(Eterm::Nil)))),
+                   eterm!({true, 'a', 1099511627776i64, {[]}}));
+        assert_eq!("{ok,#{a => <<\"b\">>}}", eterm!({ok, #{a => <<"b">>}}).to_string());
+    }
+}
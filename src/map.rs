@@ -0,0 +1,363 @@
+// Erlang map: keys are unique by `=:=` (1 and 1.0 are different keys).
+//
+// Pairs are kept in insertion order, which `MapOrder::Preserve` encodes, so
+// decoded maps are written back byte to byte. Lookup is a binary search in
+// the index of pairs, sorted in map key order, which is also the order of
+// `sorted()`. Maps built from many pairs at once (`From<Vec>`, `collect`,
+// `extend`, decoder) sort the index once; insert of a single new key and
+// remove are O(n), like `Vec::insert`.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::{fmt, mem, slice, vec};
+
+use super::Eterm;
+use super::order;
+
+
+#[derive(Clone, Default)]
+pub struct ErlMap {
+    pairs: Vec<(Eterm, Eterm)>,
+    index: Vec<usize>,          // positions of pairs, sorted by key
+}
+
+impl ErlMap {
+    pub fn new() -> ErlMap {
+        ErlMap::default()
+    }
+
+    pub fn with_capacity(capacity: usize) -> ErlMap {
+        ErlMap{pairs: Vec::with_capacity(capacity), index: Vec::with_capacity(capacity)}
+    }
+
+    pub fn len(&self) -> usize {
+        self.pairs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pairs.is_empty()
+    }
+
+    // Map of pairs with unique keys; first duplicate key is returned instead,
+    // like `binary_to_term/1` rejects it
+    pub(crate) fn from_unique(pairs: Vec<(Eterm, Eterm)>) -> Result<ErlMap, Eterm> {
+        let index = sorted_index(&pairs);
+        let duplicate = index.windows(2)
+            .filter(|w| order::compare(&pairs[w[0]].0, &pairs[w[1]].0, true) == Ordering::Equal)
+            .map(|w| w[0].max(w[1]))
+            .min();
+        match duplicate {
+            Some(pos) => Err(pairs.into_iter().nth(pos).unwrap().0),
+            None => Ok(ErlMap{pairs, index}),
+        }
+    }
+
+    // Rebuild index of pairs, where keys may repeat: value of the last pair
+    // replaces value of the first one, which keeps its position
+    fn rebuild(&mut self) {
+        let index = sorted_index(&self.pairs);
+        let mut keep = vec![true; self.pairs.len()];
+        let mut first = 0;
+        for i in 1..=index.len() {
+            if i < index.len()
+                && order::compare(&self.pairs[index[first]].0, &self.pairs[index[i]].0, true) == Ordering::Equal {
+                continue;
+            }
+            // index[first..i] are the same key in insertion order
+            if i - first > 1 {
+                let val = mem::replace(&mut self.pairs[index[i - 1]].1, Eterm::Nil);
+                self.pairs[index[first]].1 = val;
+                for &pos in &index[first + 1..i] {
+                    keep[pos] = false;
+                }
+            }
+            first = i;
+        }
+        if keep.iter().all(|&k| k) {
+            self.index = index;
+            return;
+        }
+        // new positions of kept pairs
+        let mut moved = Vec::with_capacity(keep.len());
+        let mut kept = 0;
+        for &k in &keep {
+            moved.push(kept);
+            if k {
+                kept += 1;
+            }
+        }
+        self.index = index.into_iter().filter(|&pos| keep[pos]).map(|pos| moved[pos]).collect();
+        let mut flags = keep.into_iter();
+        self.pairs.retain(|_| flags.next().unwrap());
+    }
+
+    // Position in index: Ok for existing key, Err for insertion point
+    fn search(&self, key: &Eterm) -> Result<usize, usize> {
+        self.index.binary_search_by(|&pos| order::compare(&self.pairs[pos].0, key, true))
+    }
+
+    pub fn get(&self, key: &Eterm) -> Option<&Eterm> {
+        self.search(key).ok().map(|i| &self.pairs[self.index[i]].1)
+    }
+
+    pub fn get_mut(&mut self, key: &Eterm) -> Option<&mut Eterm> {
+        match self.search(key) {
+            Ok(i) => Some(&mut self.pairs[self.index[i]].1),
+            Err(_) => None,
+        }
+    }
+
+    pub fn contains_key(&self, key: &Eterm) -> bool {
+        self.search(key).is_ok()
+    }
+
+    // Value of existing key is replaced in place and returned, like
+    // `Map#{Key := Value}`
+    pub fn insert(&mut self, key: Eterm, val: Eterm) -> Option<Eterm> {
+        match self.search(&key) {
+            Ok(i) => Some(::std::mem::replace(&mut self.pairs[self.index[i]].1, val)),
+            Err(i) => {
+                self.index.insert(i, self.pairs.len());
+                self.pairs.push((key, val));
+                None
+            },
+        }
+    }
+
+    pub fn remove(&mut self, key: &Eterm) -> Option<Eterm> {
+        let i = self.search(key).ok()?;
+        let pos = self.index.remove(i);
+        for other in self.index.iter_mut() {
+            if *other > pos {
+                *other -= 1;
+            }
+        }
+        Some(self.pairs.remove(pos).1)
+    }
+
+    pub fn clear(&mut self) {
+        self.pairs.clear();
+        self.index.clear();
+    }
+
+    // Pairs in insertion order
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(self.pairs.iter())
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Eterm> {
+        self.pairs.iter().map(|pair| &pair.0)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Eterm> {
+        self.pairs.iter().map(|pair| &pair.1)
+    }
+
+    // Pairs in map key order, like `maps:to_list/1` of small map
+    pub fn sorted(&self) -> impl Iterator<Item = (&Eterm, &Eterm)> {
+        self.index.iter().map(move |&pos| {
+            let pair = &self.pairs[pos];
+            (&pair.0, &pair.1)
+        })
+    }
+}
+
+pub struct Iter<'a>(slice::Iter<'a, (Eterm, Eterm)>);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = (&'a Eterm, &'a Eterm);
+
+    fn next(&mut self) -> Option<(&'a Eterm, &'a Eterm)> {
+        self.0.next().map(|pair| (&pair.0, &pair.1))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl<'a> IntoIterator for &'a ErlMap {
+    type Item = (&'a Eterm, &'a Eterm);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+impl IntoIterator for ErlMap {
+    type Item = (Eterm, Eterm);
+    type IntoIter = vec::IntoIter<(Eterm, Eterm)>;
+
+    fn into_iter(self) -> vec::IntoIter<(Eterm, Eterm)> {
+        self.pairs.into_iter()
+    }
+}
+
+// Positions of pairs, sorted by key; equal keys stay in insertion order
+fn sorted_index(pairs: &[(Eterm, Eterm)]) -> Vec<usize> {
+    let mut index: Vec<usize> = (0..pairs.len()).collect();
+    index.sort_by(|&a, &b| order::compare(&pairs[a].0, &pairs[b].0, true));
+    index
+}
+
+// Later values of the same key replace earlier ones
+impl Extend<(Eterm, Eterm)> for ErlMap {
+    fn extend<I: IntoIterator<Item = (Eterm, Eterm)>>(&mut self, iter: I) {
+        let new: Vec<(Eterm, Eterm)> = iter.into_iter().collect();
+        // a few keys are cheaper to insert than to sort the whole map again
+        if new.len() * 8 < self.len() {
+            for (key, val) in new {
+                self.insert(key, val);
+            }
+        } else {
+            self.pairs.extend(new);
+            self.rebuild();
+        }
+    }
+}
+
+impl FromIterator<(Eterm, Eterm)> for ErlMap {
+    fn from_iter<I: IntoIterator<Item = (Eterm, Eterm)>>(iter: I) -> ErlMap {
+        ErlMap::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl From<Vec<(Eterm, Eterm)>> for ErlMap {
+    fn from(pairs: Vec<(Eterm, Eterm)>) -> ErlMap {
+        let mut map = ErlMap{pairs, index: Vec::new()};
+        map.rebuild();
+        map
+    }
+}
+
+impl<K: Into<Eterm>, V: Into<Eterm>, S> From<HashMap<K, V, S>> for ErlMap {
+    fn from(map: HashMap<K, V, S>) -> ErlMap {
+        map.into_iter().map(|(k, v)| (k.into(), v.into())).collect()
+    }
+}
+
+impl<K: Into<Eterm>, V: Into<Eterm>> From<BTreeMap<K, V>> for ErlMap {
+    fn from(map: BTreeMap<K, V>) -> ErlMap {
+        map.into_iter().map(|(k, v)| (k.into(), v.into())).collect()
+    }
+}
+
+impl From<ErlMap> for HashMap<Eterm, Eterm> {
+    fn from(map: ErlMap) -> HashMap<Eterm, Eterm> {
+        map.into_iter().collect()
+    }
+}
+
+impl From<ErlMap> for BTreeMap<Eterm, Eterm> {
+    fn from(map: ErlMap) -> BTreeMap<Eterm, Eterm> {
+        map.into_iter().collect()
+    }
+}
+
+impl Index<&Eterm> for ErlMap {
+    type Output = Eterm;
+
+    fn index(&self, key: &Eterm) -> &Eterm {
+        self.get(key).expect("key isn't in map")
+    }
+}
+
+// Maps are equal when they have the same keys with `=:=` values, in any
+// order
+impl PartialEq for ErlMap {
+    fn eq(&self, other: &ErlMap) -> bool {
+        self.len() == other.len()
+            && self.sorted().zip(other.sorted()).all(|((k1, v1), (k2, v2))| {
+                order::compare(k1, k2, true) == Ordering::Equal && v1 == v2
+            })
+    }
+}
+
+impl fmt::Debug for ErlMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::{BTreeMap, HashMap};
+    use super::super::{Atom, Eterm};
+    use super::ErlMap;
+
+    fn atom(name: &str) -> Eterm {
+        Eterm::Atom(Atom::from(name))
+    }
+
+    #[test]
+    fn map_ops() {
+        let mut map = ErlMap::new();
+        assert_eq!(None, map.insert(atom("b"), Eterm::SmallInteger(1)));
+        assert_eq!(None, map.insert(Eterm::SmallInteger(1), atom("int")));
+        assert_eq!(None, map.insert(Eterm::Float(1.0), atom("float")));
+        assert_eq!(None, map.insert(atom("a"), Eterm::Nil));
+        assert_eq!(Some(Eterm::SmallInteger(1)), map.insert(atom("b"), Eterm::SmallInteger(2)));
+        assert_eq!(4, map.len());
+        assert_eq!(Some(&atom("float")), map.get(&Eterm::Float(1.0)));
+        assert_eq!(atom("int"), map[&Eterm::Integer(1)]);
+        assert!(!map.contains_key(&atom("c")));
+        assert_eq!(vec!(atom("b"), Eterm::SmallInteger(1), Eterm::Float(1.0), atom("a")),
+                   map.keys().cloned().collect::<Vec<_>>());
+        assert_eq!(vec!(Eterm::SmallInteger(1), Eterm::Float(1.0), atom("a"), atom("b")),
+                   map.sorted().map(|(k, _)| k.clone()).collect::<Vec<_>>());
+        *map.get_mut(&atom("a")).unwrap() = atom("x");
+        assert_eq!(Some(atom("int")), map.remove(&Eterm::SmallInteger(1)));
+        assert_eq!(None, map.remove(&Eterm::SmallInteger(1)));
+        assert_eq!(vec!((&atom("b"), &Eterm::SmallInteger(2)), (&Eterm::Float(1.0), &atom("float")),
+                        (&atom("a"), &atom("x"))),
+                   map.iter().collect::<Vec<_>>());
+        assert_eq!(Some(&atom("x")), map.get(&atom("a")));
+    }
+
+    #[test]
+    fn map_eq_convert() {
+        let one = ErlMap::from(vec!((atom("a"), Eterm::SmallInteger(1)), (atom("b"), Eterm::Nil),
+                                    (atom("a"), Eterm::SmallInteger(2))));
+        let other: ErlMap = vec!((atom("b"), Eterm::Nil), (atom("a"), Eterm::SmallInteger(2))).into_iter().collect();
+        assert_eq!(2, one.len());
+        assert_eq!(one, other);
+        assert!(one != ErlMap::from(vec!((atom("b"), Eterm::Nil), (atom("a"), Eterm::Float(2.0)))));
+        let hash: HashMap<Eterm, Eterm> = one.clone().into();
+        assert_eq!(one, ErlMap::from(hash));
+        let tree: BTreeMap<Eterm, Eterm> = other.into();
+        assert_eq!(vec!(&atom("a"), &atom("b")), tree.keys().collect::<Vec<_>>());
+        let mut typed = BTreeMap::new();
+        typed.insert("b", vec!(1));
+        assert_eq!("#{<<\"b\">> => [1]}", Eterm::Map(ErlMap::from(typed)).to_string());
+    }
+
+    #[test]
+    fn map_bulk() {
+        let int = |i: usize| Eterm::from_u64(i as u64);
+        // repeated keys keep the first position and the last value
+        let mut map = ErlMap::from(vec!((int(3), int(0)), (int(1), int(1)), (int(3), int(2)),
+                                        (int(2), int(3)), (int(1), int(4)), (int(3), int(5))));
+        assert_eq!(vec!((&int(3), &int(5)), (&int(1), &int(4)), (&int(2), &int(3))),
+                   map.iter().collect::<Vec<_>>());
+        assert_eq!(vec!(&int(1), &int(2), &int(3)), map.sorted().map(|(k, _)| k).collect::<Vec<_>>());
+        map.extend(vec!((int(0), int(6)), (int(2), int(7))));
+        assert_eq!(vec!(&int(3), &int(1), &int(2), &int(0)), map.keys().collect::<Vec<_>>());
+        assert_eq!(Some(&int(7)), map.get(&int(2)));
+        assert_eq!(Some(&int(6)), map.get(&int(0)));
+
+        let pairs: Vec<(Eterm, Eterm)> = (0..100_000).rev().map(|i| (int(i), int(i))).collect();
+        let big: ErlMap = pairs.into_iter().collect();
+        assert_eq!(100_000, big.len());
+        assert_eq!(Some(&int(12345)), big.get(&int(12345)));
+        match ErlMap::from_unique(vec!((int(1), int(0)), (int(2), int(0)), (int(2), int(1)), (int(1), int(1)))) {
+            Err(ref key) if *key == int(2) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
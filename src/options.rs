@@ -0,0 +1,195 @@
+// Encoder settings. Default is compatible with `erlang:term_to_binary/1`
+// of OTP 17..22; use `EncoderOptions::otp(Release)` for other releases.
+//
+//     let options = EncoderOptions::new()
+//         .atom_encoding(AtomEncoding::Utf8)
+//         .small_atoms(true)
+//         .compressed(6);
+
+use atom::{Atom, AtomTable};
+use super::Error;
+
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AtomEncoding {
+    Latin1,                     // ATOM_EXT / SMALL_ATOM_EXT; atoms with other chars fall back to utf8
+    Utf8,                       // ATOM_UTF8_EXT / SMALL_ATOM_UTF8_EXT
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FloatFormat {
+    Old,                        // FLOAT_EXT, "%.20e" string (minor_version 0)
+    New,                        // NEW_FLOAT_EXT, IEEE 754 (minor_version >= 1)
+}
+
+// Tags used for pids, ports and references
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum IdentifierTags {
+    Legacy,                     // PID_EXT, PORT_EXT, NEW_REFERENCE_EXT; 8-bit creation (OTP < 23)
+    BigCreation,                // NEW_PID_EXT, NEW_PORT_EXT, NEWER_REFERENCE_EXT (OTP 23)
+    V4,                         // as BigCreation + V4_PORT_EXT for 64-bit port ids (OTP >= 24)
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MapOrder {
+    Preserve,                   // pairs are written in the order they are stored
+    Sorted,                     // keys in map key order, nested maps too (`[deterministic]`)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct EncoderOptions {
+    pub(crate) atom_encoding: AtomEncoding,
+    pub(crate) small_atoms: bool,
+    pub(crate) float_format: FloatFormat,
+    pub(crate) compression: Option<u32>,
+    pub(crate) identifier_tags: IdentifierTags,
+    pub(crate) map_order: MapOrder,
+    pub(crate) fair_new_fun: bool,
+}
+
+impl Default for EncoderOptions {
+    fn default() -> EncoderOptions {
+        EncoderOptions {
+            atom_encoding: AtomEncoding::Latin1,
+            small_atoms: false,
+            float_format: FloatFormat::New,
+            compression: None,
+            identifier_tags: IdentifierTags::Legacy,
+            map_order: MapOrder::Preserve,
+            fair_new_fun: true,
+        }
+    }
+}
+
+impl EncoderOptions {
+    pub fn new() -> EncoderOptions {
+        EncoderOptions::default()
+    }
+
+    // Options, which make output identical to `term_to_binary/1` of given
+    // OTP release (17 and newer) for terms without maps. Map pairs are still
+    // written as stored; `deterministic()` gives the VM's order for small
+    // maps without atom keys (OTP 26 orders those by atom index).
+    pub fn otp(release: u32) -> EncoderOptions {
+        let options = EncoderOptions::new();
+        match release {
+            0..=22 =>
+                options,
+            23 =>
+                options.identifier_tags(IdentifierTags::BigCreation),
+            24 | 25 =>
+                options.identifier_tags(IdentifierTags::V4),
+            _ =>
+                options.identifier_tags(IdentifierTags::V4).minor_version(2),
+        }
+    }
+
+    // Same as `term_to_binary(T, [{minor_version, Version}])`
+    pub fn minor_version(self, version: u8) -> EncoderOptions {
+        match version {
+            0 => self.float_format(FloatFormat::Old).atom_encoding(AtomEncoding::Latin1).small_atoms(false),
+            1 => self.float_format(FloatFormat::New).atom_encoding(AtomEncoding::Latin1).small_atoms(false),
+            _ => self.float_format(FloatFormat::New).atom_encoding(AtomEncoding::Utf8).small_atoms(true),
+        }
+    }
+
+    pub fn atom_encoding(mut self, encoding: AtomEncoding) -> EncoderOptions {
+        self.atom_encoding = encoding;
+        self
+    }
+
+    // Use SMALL_ATOM_EXT / SMALL_ATOM_UTF8_EXT for atoms shorter than 256 bytes
+    pub fn small_atoms(mut self, small_atoms: bool) -> EncoderOptions {
+        self.small_atoms = small_atoms;
+        self
+    }
+
+    pub fn float_format(mut self, format: FloatFormat) -> EncoderOptions {
+        self.float_format = format;
+        self
+    }
+
+    // Same as `term_to_binary(T, [{compressed, Level}])`; level is 0..9,
+    // larger levels are 9. Compressed form is used only when it's smaller
+    // than uncompressed one. Deflate stream may differ from zlib's one, which
+    // the VM uses, but it decodes to the same term.
+    pub fn compressed(mut self, level: u32) -> EncoderOptions {
+        self.compression = if level == 0 { None } else { Some(level.min(9)) };
+        self
+    }
+
+    pub fn identifier_tags(mut self, tags: IdentifierTags) -> EncoderOptions {
+        self.identifier_tags = tags;
+        self
+    }
+
+    pub fn map_order(mut self, order: MapOrder) -> EncoderOptions {
+        self.map_order = order;
+        self
+    }
+
+    // Same as `term_to_binary(T, [deterministic])`: equal terms are always
+    // encoded to the same bytes.
+    pub fn deterministic(self) -> EncoderOptions {
+        self.map_order(MapOrder::Sorted)
+    }
+
+    // Calculate real NEW_FUN_EXT size, like OTP does, instead of writing 0
+    // (`binary_to_term` doesn't check it). Requires extra memory.
+    pub fn fair_new_fun(mut self, fair_new_fun: bool) -> EncoderOptions {
+        self.fair_new_fun = fair_new_fun;
+        self
+    }
+}
+
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct DecoderOptions {
+    pub(crate) strings_as_lists: bool,
+    pub(crate) atom_table: Option<AtomTable>,
+    pub(crate) max_depth: usize,
+}
+
+impl Default for DecoderOptions {
+    fn default() -> DecoderOptions {
+        DecoderOptions {
+            strings_as_lists: false,
+            atom_table: None,
+            max_depth: 512,
+        }
+    }
+}
+
+impl DecoderOptions {
+    pub fn new() -> DecoderOptions {
+        DecoderOptions::default()
+    }
+
+    // Decode STRING_EXT to `List` of `SmallInteger` instead of `String`
+    pub fn strings_as_lists(mut self, strings_as_lists: bool) -> DecoderOptions {
+        self.strings_as_lists = strings_as_lists;
+        self
+    }
+
+    // Intern atoms in given table, e.g. to share text of repeated atoms or to
+    // limit number of atoms, created by untrusted input. `None` (default)
+    // allocates each decoded atom.
+    pub fn atom_table(mut self, atom_table: Option<AtomTable>) -> DecoderOptions {
+        self.atom_table = atom_table;
+        self
+    }
+
+    // Terms nested deeper (e.g. `[[[...]]]`) are rejected with
+    // `Error::TooDeep`; top-level term has depth 1. Default is 512.
+    pub fn max_depth(mut self, max_depth: usize) -> DecoderOptions {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub(crate) fn atom(&self, name: &str) -> Result<Atom, Error> {
+        match self.atom_table {
+            Some(ref table) => table.intern(name),
+            None => Ok(Atom::from(name)),
+        }
+    }
+}
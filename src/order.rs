@@ -0,0 +1,462 @@
+// Erlang term order:
+//
+//     number < atom < reference < fun < port < pid < tuple < map < nil < list < bit string
+//
+// `exact` comparison is the one used for map keys (and `=:=`): integers and
+// floats are never equal, all integers go before all floats.
+// Pids, ports, references and funs are ordered by node (module) name first and
+// then by their numbers, which is close, but not always identical to what BEAM
+// does for terms from different nodes.
+//
+// `Ord` for `Eterm` is the standard order (`<`, `lists:sort/1`), where terms
+// equal by `==` (like 1 and 1.0) are ordered by exact comparison; `Eq` is `=:=`.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+
+use num::bigint::BigInt;
+use num::traits::{FromPrimitive, ToPrimitive};
+
+use super::{Eterm, Reference, Pid};
+
+
+fn rank(term: &Eterm) -> u8 {
+    match *term {
+        Eterm::SmallInteger(_) | Eterm::Integer(_) | Eterm::BigNum(_) | Eterm::Float(_) => 0,
+        Eterm::Atom(_) => 1,
+        Eterm::Reference(_) => 2,
+        Eterm::Fun(_) | Eterm::NewFun(_) | Eterm::Export(_) => 3,
+        Eterm::Port(_) => 4,
+        Eterm::Pid(_) => 5,
+        Eterm::Tuple(_) => 6,
+        Eterm::Map(_) => 7,
+        Eterm::Nil => 8,
+        Eterm::String(_) | Eterm::List(_) => 9,
+        Eterm::Binary(_) | Eterm::BitBinary(_) => 10,
+    }
+}
+const LIST_RANK: u8 = 9;
+
+fn is_list(term: &Eterm) -> bool {
+    matches!(*term, Eterm::Nil | Eterm::String(_) | Eterm::List(_))
+}
+
+pub(crate) fn compare(a: &Eterm, b: &Eterm, exact: bool) -> Ordering {
+    if is_list(a) && is_list(b) {
+        return compare_lists(a, b, exact);
+    }
+    let (rank_a, rank_b) = (rank(a), rank(b));
+    if rank_a != rank_b {
+        return rank_a.cmp(&rank_b);
+    }
+    match (a, b) {
+        (&Eterm::Float(x), &Eterm::Float(y)) =>
+            if exact { x.total_cmp(&y) } else { x.partial_cmp(&y).unwrap_or_else(|| x.total_cmp(&y)) },
+        (&Eterm::Float(x), _) =>
+            if exact { Ordering::Greater } else { compare_int_float(&to_bigint(b), x).reverse() },
+        (_, &Eterm::Float(y)) =>
+            if exact { Ordering::Less } else { compare_int_float(&to_bigint(a), y) },
+        (&Eterm::BigNum(_), _) | (_, &Eterm::BigNum(_)) =>
+            to_bigint(a).cmp(&to_bigint(b)),
+        (&Eterm::SmallInteger(_), _) | (&Eterm::Integer(_), _) =>
+            to_i64(a).cmp(&to_i64(b)),
+        (Eterm::Atom(x), Eterm::Atom(y)) =>
+            x.cmp(y),
+        (Eterm::Reference(x), Eterm::Reference(y)) =>
+            x.node.cmp(&y.node)
+            .then_with(|| reference_words(x).cmp(&reference_words(y)))
+            .then(x.creation.cmp(&y.creation)),
+        (Eterm::Port(x), Eterm::Port(y)) =>
+            x.node.cmp(&y.node)
+            .then(x.id.cmp(&y.id))
+            .then(x.creation.cmp(&y.creation)),
+        (Eterm::Pid(x), Eterm::Pid(y)) =>
+            x.node.cmp(&y.node)
+            .then(x.id.cmp(&y.id))
+            .then(x.serial.cmp(&y.serial))
+            .then(x.creation.cmp(&y.creation)),
+        (Eterm::Tuple(x), Eterm::Tuple(y)) =>
+            x.len().cmp(&y.len())
+            .then_with(|| compare_seq(x.iter(), y.iter(), exact)),
+        (Eterm::Map(x), Eterm::Map(y)) =>
+            x.len().cmp(&y.len())
+            .then_with(|| {
+                // keys are always compared exactly
+                compare_seq(x.sorted().map(|pair| pair.0), y.sorted().map(|pair| pair.0), true)
+                    .then_with(|| compare_seq(x.sorted().map(|pair| pair.1), y.sorted().map(|pair| pair.1), exact))
+            }),
+        _ if rank_a == 10 => {
+            let (x, x_bits) = bit_string(a);
+            let (y, y_bits) = bit_string(b);
+            compare_bits(x, x_bits, y, y_bits)
+        },
+        _ => compare_funs(a, b, exact),
+    }
+}
+
+fn compare_seq<'a, I, J>(x: I, y: J, exact: bool) -> Ordering
+    where I: Iterator<Item = &'a Eterm>, J: Iterator<Item = &'a Eterm> {
+    for (a, b) in x.zip(y) {
+        let ord = compare(a, b, exact);
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    Ordering::Equal
+}
+
+fn to_i64(term: &Eterm) -> i64 {
+    match *term {
+        Eterm::SmallInteger(num) => num as i64,
+        Eterm::Integer(num) => num as i64,
+        _ => unreachable!(),
+    }
+}
+
+fn to_bigint(term: &Eterm) -> BigInt {
+    match *term {
+        Eterm::BigNum(ref num) => num.clone(),
+        _ => BigInt::from(to_i64(term)),
+    }
+}
+
+fn compare_int_float(int: &BigInt, float: f64) -> Ordering {
+    if float.is_nan() {
+        return Ordering::Less;
+    }
+    if float.is_infinite() {
+        return if float > 0.0 { Ordering::Less } else { Ordering::Greater };
+    }
+    let floor = float.floor();
+    match int.cmp(&BigInt::from_f64(floor).unwrap()) {
+        Ordering::Equal if float > floor => Ordering::Less,
+        ord => ord,
+    }
+}
+
+// Most significant word goes last in `Reference.id`
+fn reference_words(reference: &Reference) -> Vec<&[u8]> {
+    reference.id.chunks(4).rev().collect()
+}
+
+// Heads of (possibly nested or improper) list and its tail, which is never a list
+pub(crate) fn list_parts(term: &Eterm) -> (Vec<Cow<'_, Eterm>>, Cow<'_, Eterm>) {
+    let mut heads = Vec::new();
+    let mut cur = term;
+    loop {
+        match *cur {
+            Eterm::String(ref s) => {
+                heads.extend(s.iter().map(|b| Cow::Owned(Eterm::SmallInteger(*b))));
+                return (heads, Cow::Owned(Eterm::Nil));
+            },
+            Eterm::List(ref elems) if !elems.is_empty() => {
+                let (tail, elems) = elems.split_last().unwrap();
+                heads.extend(elems.iter().map(Cow::Borrowed));
+                cur = tail;
+            },
+            Eterm::List(_) =>
+                return (heads, Cow::Owned(Eterm::Nil)),
+            _ =>
+                return (heads, Cow::Borrowed(cur)),
+        }
+    }
+}
+
+fn compare_lists(a: &Eterm, b: &Eterm, exact: bool) -> Ordering {
+    let (heads_a, tail_a) = list_parts(a);
+    let (heads_b, tail_b) = list_parts(b);
+    let ord = compare_seq(heads_a.iter().map(|h| h.as_ref()), heads_b.iter().map(|h| h.as_ref()), exact);
+    if ord != Ordering::Equal {
+        return ord;
+    }
+    // shorter list's tail is compared with the rest of the longer one
+    match heads_a.len().cmp(&heads_b.len()) {
+        Ordering::Equal => match (tail_a.as_ref(), tail_b.as_ref()) {
+            (&Eterm::Nil, &Eterm::Nil) => Ordering::Equal,
+            (tail_a, tail_b) => compare(tail_a, tail_b, exact),
+        },
+        Ordering::Less => rank(&tail_a).cmp(&LIST_RANK),
+        Ordering::Greater => LIST_RANK.cmp(&rank(&tail_b)),
+    }
+}
+
+fn bit_string(term: &Eterm) -> (&[u8], usize) {
+    match *term {
+        Eterm::Binary(ref data) => (data, data.len() * 8),
+        Eterm::BitBinary(ref bit_bin) if !bit_bin.data.is_empty() =>
+            (&bit_bin.data, (bit_bin.data.len() - 1) * 8 + bit_bin.bits as usize),
+        Eterm::BitBinary(_) => (&[], 0),
+        _ => unreachable!(),
+    }
+}
+
+fn compare_bits(x: &[u8], x_bits: usize, y: &[u8], y_bits: usize) -> Ordering {
+    let common = x_bits.min(y_bits);
+    let full = common / 8;
+    x[..full].cmp(&y[..full])
+        .then_with(|| {
+            let rest = common % 8;
+            if rest == 0 {
+                return Ordering::Equal;
+            }
+            let mask = 0xFFu8 << (8 - rest);
+            (x[full] & mask).cmp(&(y[full] & mask))
+        })
+        .then(x_bits.cmp(&y_bits))
+}
+
+// Local funs go before external ones
+fn compare_funs(a: &Eterm, b: &Eterm, exact: bool) -> Ordering {
+    compare_local_funs(a, b, exact)
+        .then_with(|| fun_details(a).cmp(&fun_details(b)))
+}
+
+fn compare_local_funs(a: &Eterm, b: &Eterm, exact: bool) -> Ordering {
+    match (a, b) {
+        (Eterm::Export(x), Eterm::Export(y)) =>
+            x.module.cmp(&y.module)
+            .then_with(|| x.function.cmp(&y.function))
+            .then(x.arity.cmp(&y.arity)),
+        (Eterm::Export(_), _) => Ordering::Greater,
+        (_, Eterm::Export(_)) => Ordering::Less,
+        _ => {
+            let (x_module, x_index, x_uniq, x_vars) = local_fun(a);
+            let (y_module, y_index, y_uniq, y_vars) = local_fun(b);
+            x_module.cmp(y_module)
+                .then(x_index.cmp(&y_index))
+                .then(x_uniq.cmp(&y_uniq))
+                .then(x_vars.len().cmp(&y_vars.len()))
+                .then_with(|| compare_seq(x_vars.iter(), y_vars.iter(), exact))
+        }
+    }
+}
+
+fn local_fun(term: &Eterm) -> (&str, u32, u32, &[Eterm]) {
+    match *term {
+        Eterm::Fun(ref fun) => (&fun.module, fun.index, fun.uniq, &fun.free_vars),
+        Eterm::NewFun(ref fun) => (&fun.module, fun.old_index, fun.old_uniq, &fun.free_vars),
+        _ => unreachable!(),
+    }
+}
+
+type PidFields<'a> = (&'a str, u32, u32, u32);
+
+// The rest of fun fields, so different funs are never equal
+fn fun_details(term: &Eterm) -> (u8, u8, u32, &[u8], Option<PidFields<'_>>) {
+    fn pid(pid: &Pid) -> PidFields<'_> {
+        (&pid.node, pid.id, pid.serial, pid.creation)
+    }
+    match *term {
+        Eterm::Fun(ref fun) => (0, 0, 0, &[], Some(pid(&fun.pid))),
+        Eterm::NewFun(ref fun) => (1, fun.arity, fun.index, &fun.uniq, Some(pid(&fun.pid))),
+        _ => (2, 0, 0, &[], None),
+    }
+}
+
+
+impl PartialEq for Eterm {
+    fn eq(&self, other: &Eterm) -> bool {
+        compare(self, other, true) == Ordering::Equal
+    }
+}
+
+impl Eq for Eterm {}
+
+impl PartialOrd for Eterm {
+    fn partial_cmp(&self, other: &Eterm) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Eterm {
+    fn cmp(&self, other: &Eterm) -> Ordering {
+        compare(self, other, false)
+            .then_with(|| compare(self, other, true))
+    }
+}
+
+impl Eterm {
+    // Erlang's `=:=`; same as `==` on `Eterm`
+    pub fn eq_exact(&self, other: &Eterm) -> bool {
+        compare(self, other, true) == Ordering::Equal
+    }
+
+    // Erlang's `==`: integers and floats are equal when their values are
+    pub fn eq_arith(&self, other: &Eterm) -> bool {
+        compare(self, other, false) == Ordering::Equal
+    }
+}
+
+// Consistent with `=:=`: every representation of the same term (e.g.
+// `SmallInteger` / `Integer` / `BigNum`, `String` / `List`) hashes the same.
+impl Hash for Eterm {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        if is_list(self) {
+            let (heads, tail) = list_parts(self);
+            state.write_u8(LIST_RANK);
+            state.write_usize(heads.len());
+            for head in heads.iter() {
+                head.hash(state);
+            }
+            if *tail != Eterm::Nil {
+                tail.hash(state);
+            }
+            return;
+        }
+        state.write_u8(rank(self));
+        match *self {
+            Eterm::SmallInteger(_) | Eterm::Integer(_) =>
+                to_i64(self).hash(state),
+            Eterm::BigNum(ref num) => match num.to_i64() {
+                Some(num) => num.hash(state),
+                None => num.hash(state),
+            },
+            Eterm::Float(num) =>
+                num.to_bits().hash(state),
+            Eterm::Atom(ref atom) =>
+                atom.hash(state),
+            Eterm::Reference(ref reference) => {
+                reference.node.hash(state);
+                reference.id.hash(state);
+                reference.creation.hash(state);
+            },
+            Eterm::Port(ref port) => {
+                port.node.hash(state);
+                port.id.hash(state);
+                port.creation.hash(state);
+            },
+            Eterm::Pid(ref pid) => {
+                pid.node.hash(state);
+                pid.id.hash(state);
+                pid.serial.hash(state);
+                pid.creation.hash(state);
+            },
+            Eterm::Tuple(ref elems) =>
+                elems.hash(state),
+            Eterm::Map(ref pairs) => {
+                state.write_usize(pairs.len());
+                for (key, val) in pairs.sorted() {
+                    key.hash(state);
+                    val.hash(state);
+                }
+            },
+            Eterm::Binary(_) | Eterm::BitBinary(_) => {
+                let (data, bits) = bit_string(self);
+                data[..bits / 8].hash(state);
+                if bits % 8 != 0 {
+                    state.write_u8(data[bits / 8] & (0xFFu8 << (8 - bits % 8)));
+                }
+                state.write_usize(bits);
+            },
+            Eterm::Export(ref export) => {
+                export.module.hash(state);
+                export.function.hash(state);
+                export.arity.hash(state);
+            },
+            Eterm::Fun(_) | Eterm::NewFun(_) => {
+                let (module, index, uniq, vars) = local_fun(self);
+                (module, index, uniq, vars).hash(state);
+                fun_details(self).hash(state);
+            },
+            Eterm::Nil | Eterm::String(_) | Eterm::List(_) => unreachable!(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::{Atom, Eterm, BitBinary};
+    use super::compare;
+    use std::cmp::Ordering;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{BTreeSet, HashSet};
+    use std::hash::{Hash, Hasher};
+    use num::bigint;
+    use num::traits::FromPrimitive;
+
+    fn atom(name: &str) -> Eterm {
+        Eterm::Atom(Atom::from(name))
+    }
+
+    #[test]
+    fn order_types() {
+        let big: bigint::BigInt = FromPrimitive::from_i64(-10000000000).unwrap();
+        // sorted in Erlang term order
+        let terms = vec!(
+            Eterm::BigNum(big),
+            Eterm::SmallInteger(1),
+            Eterm::Float(1.5),
+            Eterm::Integer(300),
+            atom("a"),
+            atom("b"),
+            Eterm::Tuple(vec!(atom("z"))),
+            Eterm::Tuple(vec!(Eterm::SmallInteger(1), Eterm::SmallInteger(2))),
+            Eterm::Map(vec!().into()),
+            Eterm::Nil,
+            Eterm::List(vec!(Eterm::SmallInteger(1), atom("improper"))),
+            Eterm::String(b"ab".to_vec()),
+            Eterm::List(vec!(Eterm::SmallInteger(97), Eterm::SmallInteger(98), Eterm::SmallInteger(0), Eterm::Nil)),
+            Eterm::BitBinary(BitBinary{bits: 1, data: vec!(128)}),
+            Eterm::Binary(vec!(128)),
+            Eterm::Binary(vec!(128, 0)));
+        for (i, a) in terms.iter().enumerate() {
+            for (j, b) in terms.iter().enumerate() {
+                assert_eq!(i.cmp(&j), compare(a, b, false), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn order_numbers() {
+        assert_eq!(Ordering::Equal, compare(&Eterm::SmallInteger(1), &Eterm::Float(1.0), false));
+        assert_eq!(Ordering::Less, compare(&Eterm::SmallInteger(1), &Eterm::Float(1.0), true));
+        assert_eq!(Ordering::Less, compare(&Eterm::Integer(2), &Eterm::Float(1.0), true));
+        assert_eq!(Ordering::Equal, compare(&Eterm::SmallInteger(1), &Eterm::Integer(1), true));
+        assert_eq!(Ordering::Greater, compare(&Eterm::Integer(2), &Eterm::Float(1.5), false));
+        assert_eq!(Ordering::Equal,
+                   compare(&Eterm::String(b"a".to_vec()),
+                           &Eterm::List(vec!(Eterm::Integer(97), Eterm::Nil)), true));
+    }
+
+    fn hash(term: &Eterm) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        term.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn order_ord_hash() {
+        let big: bigint::BigInt = FromPrimitive::from_i64(1).unwrap();
+        let same = [
+            (Eterm::SmallInteger(1), Eterm::BigNum(big)),
+            (Eterm::String(b"ab".to_vec()),
+             Eterm::List(vec!(Eterm::SmallInteger(97), Eterm::List(vec!(Eterm::Integer(98), Eterm::Nil))))),
+            (Eterm::Nil, Eterm::List(vec!(Eterm::Nil))),
+            (Eterm::Binary(vec!(1, 2)), Eterm::BitBinary(BitBinary{bits: 8, data: vec!(1, 2)})),
+            (Eterm::BitBinary(BitBinary{bits: 1, data: vec!(0xFF)}),
+             Eterm::BitBinary(BitBinary{bits: 1, data: vec!(0x80)})),
+            (Eterm::Map(vec!((atom("a"), Eterm::Nil), (atom("b"), Eterm::Nil)).into()),
+             Eterm::Map(vec!((atom("b"), Eterm::Nil), (atom("a"), Eterm::Nil)).into()))];
+        for (a, b) in same.iter() {
+            assert_eq!(a, b);
+            assert_eq!(hash(a), hash(b), "{:?} vs {:?}", a, b);
+        }
+
+        let (int, float) = (Eterm::SmallInteger(1), Eterm::Float(1.0));
+        assert!(int != float);
+        assert!(int.eq_arith(&float) && !int.eq_exact(&float));
+        assert!(Eterm::Float(-0.0) != Eterm::Float(0.0));
+        // `==` terms are ordered by exact comparison
+        let mut terms = vec!(Eterm::Float(2.0), atom("a"), float.clone(), Eterm::Integer(2), int.clone(), Eterm::Nil);
+        terms.sort();
+        assert_eq!(vec!(int.clone(), float.clone(), Eterm::Integer(2), Eterm::Float(2.0), atom("a"), Eterm::Nil),
+                   terms);
+
+        let set: BTreeSet<Eterm> = vec!(Eterm::Integer(1), int.clone(), float.clone()).into_iter().collect();
+        assert_eq!(2, set.len());
+        let set: HashSet<Eterm> = vec!(Eterm::Integer(1), int, float).into_iter().collect();
+        assert_eq!(2, set.len());
+    }
+}
@@ -0,0 +1,728 @@
+// Parser of Erlang term syntax (what `erl_parse:parse_term/1` accepts, plus
+// `fun M:F/A`), so `"{ok, [1,2|3]}".parse::<Eterm>()` works.
+//
+// Integers get the same representation decoder would produce (see
+// `Eterm::from_i128`), proper lists are `List`s and strings are `String`s
+// (or `List`s, when some character doesn't fit into a byte). Trailing `.`
+// and `%` comments are allowed. Binaries are limited to 16 MiB and nesting
+// to 512 levels, so that short text can't take all memory or stack.
+
+use std::str::FromStr;
+
+use num::bigint::{BigInt, Sign};
+use num::traits::{Num, ToPrimitive};
+
+use super::{Eterm, Atom, BitBinary, ErlMap, Export, Error};
+use super::display::{is_lower, is_name_char, RESERVED_WORDS};
+use validate::check_atom;
+
+
+const MAX_BINARY_BITS: usize = 8 << 24;
+const MAX_DEPTH: usize = 512;         // same as DecoderOptions' default
+
+fn integer(num: BigInt) -> Eterm {
+    match num.to_i128() {
+        Some(num) => Eterm::from_i128(num),
+        None => Eterm::BigNum(num),
+    }
+}
+
+// Binary under construction
+struct Bits {
+    data: Vec<u8>,
+    len: usize,
+}
+
+impl Bits {
+    fn push_bit(&mut self, bit: bool) {
+        if self.len % 8 == 0 {
+            self.data.push(0);
+        }
+        if bit {
+            *self.data.last_mut().unwrap() |= 0x80 >> (self.len % 8);
+        }
+        self.len += 1;
+    }
+
+    // Low `size` bits of two's complement value
+    fn push_int(&mut self, value: &BigInt, size: usize, little: bool) {
+        let bytes = value.to_signed_bytes_le();
+        let fill = if value.sign() == Sign::Minus { 0xFF } else { 0 };
+        let byte = |i: usize| bytes.get(i).cloned().unwrap_or(fill);
+        if self.len % 8 == 0 && size % 8 == 0 {
+            if little {
+                self.data.extend((0..size / 8).map(byte));
+            } else {
+                self.data.extend((0..size / 8).rev().map(byte));
+            }
+            self.len += size;
+            return;
+        }
+        let bit = |i: usize| (byte(i / 8) >> (i % 8)) & 1 == 1;
+        if little {
+            // whole bytes go first, then high bits in the last partial byte
+            for byte in 0..size / 8 {
+                for i in (0..8).rev() {
+                    self.push_bit(bit(byte * 8 + i));
+                }
+            }
+            for i in (0..size % 8).rev() {
+                self.push_bit(bit(size / 8 * 8 + i));
+            }
+        } else {
+            for i in (0..size).rev() {
+                self.push_bit(bit(i));
+            }
+        }
+    }
+
+    fn into_term(self) -> Eterm {
+        match self.len % 8 {
+            0 => Eterm::Binary(self.data),
+            bits => Eterm::BitBinary(BitBinary{bits: bits as u8, data: self.data}),
+        }
+    }
+}
+
+enum SegmentValue {
+    Int(BigInt),
+    Float(f64),
+    Str(String),
+}
+
+#[derive(PartialEq)]
+enum SegmentType {
+    Integer,
+    Float,
+    Utf8,
+    Utf16,
+    Utf32,
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,                 // in bytes
+    depth: usize,               // of the term being parsed
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, pos: usize, msg: String) -> Result<T, Error> {
+        let before = &self.src[..pos];
+        let line = before.matches('\n').count() + 1;
+        let column = before.rsplit('\n').next().unwrap().chars().count() + 1;
+        Err(Error::Syntax(line, column, msg))
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> Result<T, Error> {
+        match self.peek() {
+            Some(c) => self.error(self.pos, format!("unexpected '{}', expected {}", c, expected)),
+            None => self.error(self.pos, format!("unexpected end of input, expected {}", expected)),
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.src[self.pos..].chars().nth(n)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_ws(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '%' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.bump();
+                }
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    // Skips whitespace and `token`, if it's next
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_ws();
+        if self.src[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), Error> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            self.unexpected(&format!("'{}'", token))
+        }
+    }
+
+    fn term(&mut self) -> Result<Eterm, Error> {
+        self.skip_ws();
+        if self.depth >= MAX_DEPTH {
+            return self.error(self.pos, format!("term is nested deeper than {} levels", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let term = self.concrete_term();
+        self.depth -= 1;
+        term
+    }
+
+    // Containers have their own functions, so that each level of nesting
+    // takes little stack
+    fn concrete_term(&mut self) -> Result<Eterm, Error> {
+        match self.peek() {
+            Some('{') => self.tuple(),
+            Some('[') => self.list(),
+            Some('#') => self.map(),
+            Some('<') if self.peek_at(1) == Some('<') => self.binary(),
+            _ => self.scalar(),
+        }
+    }
+
+    fn scalar(&mut self) -> Result<Eterm, Error> {
+        match self.peek() {
+            Some('"') => {
+                let s = self.string()?;
+                Ok(string_term(&s))
+            },
+            Some('$') => {
+                self.bump();
+                Ok(Eterm::from_i64(self.char_literal()? as i64))
+            },
+            Some(c) if c == '-' || c == '+' => {
+                self.bump();
+                self.skip_ws();
+                match self.peek() {
+                    Some(d) if d.is_ascii_digit() => self.number(c == '-'),
+                    _ => self.unexpected("number"),
+                }
+            },
+            Some(c) if c.is_ascii_digit() => self.number(false),
+            Some('\'') => Ok(Eterm::Atom(self.atom()?)),
+            Some(c) if is_lower(c) => {
+                if self.src[self.pos..].starts_with("fun") && !self.peek_at(3).is_some_and(is_name_char) {
+                    self.pos += 3;
+                    return self.export();
+                }
+                Ok(Eterm::Atom(self.atom()?))
+            },
+            _ => self.unexpected("term"),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Atom, Error> {
+        self.skip_ws();
+        let start = self.pos;
+        let name = match self.peek() {
+            Some('\'') => self.quoted('\'')?,
+            Some(c) if is_lower(c) => {
+                while self.peek().is_some_and(is_name_char) {
+                    self.bump();
+                }
+                let name = &self.src[start..self.pos];
+                if RESERVED_WORDS.contains(&name) {
+                    return self.error(start, format!("reserved word '{}' should be quoted", name));
+                }
+                String::from(name)
+            },
+            _ => return self.unexpected("atom"),
+        };
+        if let Err(err) = check_atom(&name) {
+            return self.error(start, err.to_string());
+        }
+        Ok(Atom::from(name))
+    }
+
+    // `fun M:F/A`, after `fun`
+    fn export(&mut self) -> Result<Eterm, Error> {
+        let module = self.atom()?;
+        self.expect(":")?;
+        let function = self.atom()?;
+        self.expect("/")?;
+        self.skip_ws();
+        let start = self.pos;
+        match self.digits(10)?.parse::<u8>() {
+            Ok(arity) => Ok(Eterm::Export(Export{module, function, arity})),
+            Err(_) => self.error(start, String::from("arity should be 0..255")),
+        }
+    }
+
+    // Digits (and `_` separators between them)
+    fn digits(&mut self, radix: u32) -> Result<String, Error> {
+        let mut digits = String::new();
+        loop {
+            match self.peek() {
+                Some(c) if c.is_digit(radix) => digits.push(c),
+                Some('_') if !digits.is_empty() && self.peek_at(1).is_some_and(|c| c.is_digit(radix)) => (),
+                _ => break,
+            }
+            self.bump();
+        }
+        if digits.is_empty() {
+            return self.unexpected(&format!("base {} digit", radix));
+        }
+        Ok(digits)
+    }
+
+    fn number(&mut self, negative: bool) -> Result<Eterm, Error> {
+        let start = self.pos;
+        let int = self.digits(10)?;
+        let sign = if negative { "-" } else { "" };
+        if self.peek() == Some('#') {
+            let base = match int.parse::<u32>() {
+                Ok(base) if (2..=36).contains(&base) => base,
+                _ => return self.error(start, format!("bad base {}", int)),
+            };
+            self.bump();
+            let digits = self.digits(base)?;
+            let num = BigInt::from_str_radix(&format!("{}{}", sign, digits), base).unwrap();
+            return Ok(integer(num));
+        }
+        if self.peek() == Some('.') && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()) {
+            self.bump();
+            let frac = self.digits(10)?;
+            let mut exp = String::new();
+            if let Some('e') | Some('E') = self.peek() {
+                self.bump();
+                if let Some(c) = self.peek().filter(|c| *c == '-' || *c == '+') {
+                    exp.push(c);
+                    self.bump();
+                }
+                exp.push_str(&self.digits(10)?);
+            }
+            let num = format!("{}{}.{}e{}", sign, int, frac, if exp.is_empty() { "0" } else { &exp })
+                .parse::<f64>().unwrap();
+            if !num.is_finite() {
+                return self.error(start, String::from("float is out of range"));
+            }
+            return Ok(Eterm::Float(num));
+        }
+        Ok(integer(BigInt::from_str_radix(&format!("{}{}", sign, int), 10).unwrap()))
+    }
+
+    // Escape sequence, after backslash
+    fn escape(&mut self) -> Result<char, Error> {
+        let start = self.pos - 1;
+        let code = match self.bump() {
+            Some('b') => 8,
+            Some('d') => 127,
+            Some('e') => 27,
+            Some('f') => 12,
+            Some('n') => 10,
+            Some('r') => 13,
+            Some('s') => 32,
+            Some('t') => 9,
+            Some('v') => 11,
+            Some(c @ '0'..='7') => {
+                let mut code = c.to_digit(8).unwrap();
+                for _ in 0..2 {
+                    match self.peek().and_then(|c| c.to_digit(8)) {
+                        Some(d) => code = code * 8 + d,
+                        None => break,
+                    }
+                    self.bump();
+                }
+                code
+            },
+            Some('x') => {
+                let hex = if self.peek() == Some('{') {
+                    self.bump();
+                    let hex = self.digits(16)?;
+                    self.expect("}")?;
+                    hex
+                } else {
+                    let mut hex = String::new();
+                    for _ in 0..2 {
+                        match self.peek().filter(|c| c.is_ascii_hexdigit()) {
+                            Some(c) => hex.push(c),
+                            None => return self.unexpected("hex digit"),
+                        }
+                        self.bump();
+                    }
+                    hex
+                };
+                u32::from_str_radix(&hex, 16).unwrap_or(u32::MAX)
+            },
+            Some('^') => match self.bump() {
+                Some(c) => c as u32 & 31,
+                None => return self.unexpected("control character"),
+            },
+            Some(c) => c as u32,
+            None => return self.unexpected("escape sequence"),
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error(start, String::from("bad character code")),
+        }
+    }
+
+    // `$c`, after `$`
+    fn char_literal(&mut self) -> Result<char, Error> {
+        match self.bump() {
+            Some('\\') => self.escape(),
+            Some(c) => Ok(c),
+            None => self.unexpected("character"),
+        }
+    }
+
+    fn quoted(&mut self, quote: char) -> Result<String, Error> {
+        let start = self.pos;
+        self.bump();
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('\\') => s.push(self.escape()?),
+                Some(c) if c == quote => return Ok(s),
+                Some(c) => s.push(c),
+                None => return self.error(start, format!("unterminated {}", if quote == '"' { "string" } else { "atom" })),
+            }
+        }
+    }
+
+    // Adjacent string literals are concatenated
+    fn string(&mut self) -> Result<String, Error> {
+        let mut s = self.quoted('"')?;
+        loop {
+            self.skip_ws();
+            if self.peek() != Some('"') {
+                return Ok(s);
+            }
+            s.push_str(&self.quoted('"')?);
+        }
+    }
+
+    fn tuple(&mut self) -> Result<Eterm, Error> {
+        self.bump();
+        let mut elems = Vec::new();
+        if self.eat("}") {
+            return Ok(Eterm::Tuple(elems));
+        }
+        loop {
+            elems.push(self.term()?);
+            if self.eat("}") {
+                return Ok(Eterm::Tuple(elems));
+            }
+            if !self.eat(",") {
+                return self.unexpected("',' or '}'");
+            }
+        }
+    }
+
+    fn list(&mut self) -> Result<Eterm, Error> {
+        self.bump();
+        if self.eat("]") {
+            return Ok(Eterm::Nil);
+        }
+        let mut elems = Vec::new();
+        loop {
+            elems.push(self.term()?);
+            if self.eat("]") {
+                elems.push(Eterm::Nil);
+                return Ok(Eterm::List(elems));
+            }
+            if self.eat("|") {
+                elems.push(self.term()?);
+                self.expect("]")?;
+                return Ok(Eterm::List(elems));
+            }
+            if !self.eat(",") {
+                return self.unexpected("',', '|' or ']'");
+            }
+        }
+    }
+
+    // Later values of the same key replace earlier ones
+    fn map(&mut self) -> Result<Eterm, Error> {
+        self.expect("#{")?;
+        let mut pairs = Vec::new();
+        if self.eat("}") {
+            return Ok(Eterm::Map(ErlMap::from(pairs)));
+        }
+        loop {
+            let key = self.term()?;
+            self.expect("=>")?;
+            let val = self.term()?;
+            pairs.push((key, val));
+            if self.eat("}") {
+                return Ok(Eterm::Map(ErlMap::from(pairs)));
+            }
+            if !self.eat(",") {
+                return self.unexpected("',' or '}'");
+            }
+        }
+    }
+
+    fn binary(&mut self) -> Result<Eterm, Error> {
+        self.pos += 2;
+        let mut bits = Bits{data: Vec::new(), len: 0};
+        if self.eat(">>") {
+            return Ok(bits.into_term());
+        }
+        loop {
+            self.segment(&mut bits)?;
+            if self.eat(">>") {
+                return Ok(bits.into_term());
+            }
+            if !self.eat(",") {
+                return self.unexpected("',' or '>>'");
+            }
+        }
+    }
+
+    // Value[:Size][/Type-Specifiers]
+    fn segment(&mut self, bits: &mut Bits) -> Result<(), Error> {
+        self.skip_ws();
+        let start = self.pos;
+        let value = if self.peek() == Some('"') {
+            SegmentValue::Str(self.string()?)
+        } else {
+            match self.term()? {
+                Eterm::Float(num) => SegmentValue::Float(num),
+                Eterm::SmallInteger(num) => SegmentValue::Int(BigInt::from(num)),
+                Eterm::Integer(num) => SegmentValue::Int(BigInt::from(num)),
+                Eterm::BigNum(num) => SegmentValue::Int(num),
+                _ => return self.error(start, String::from("binary segment should be a number or a string")),
+            }
+        };
+        let size = if self.eat(":") {
+            self.skip_ws();
+            let size_pos = self.pos;
+            match self.digits(10)?.parse::<usize>() {
+                Ok(size) => Some(size),
+                Err(_) => return self.error(size_pos, String::from("segment size is too large")),
+            }
+        } else {
+            None
+        };
+        let mut seg_type = None;
+        let (mut little, mut unit) = (false, None);
+        if self.eat("/") {
+            loop {
+                self.skip_ws();
+                let spec_pos = self.pos;
+                while self.peek().is_some_and(is_name_char) {
+                    self.bump();
+                }
+                match &self.src[spec_pos..self.pos] {
+                    "integer" => seg_type = Some(SegmentType::Integer),
+                    "float" => seg_type = Some(SegmentType::Float),
+                    "utf8" => seg_type = Some(SegmentType::Utf8),
+                    "utf16" => seg_type = Some(SegmentType::Utf16),
+                    "utf32" => seg_type = Some(SegmentType::Utf32),
+                    "big" => little = false,
+                    "little" => little = true,
+                    "native" => little = cfg!(target_endian = "little"),
+                    "signed" | "unsigned" => (),
+                    "unit" => {
+                        self.expect(":")?;
+                        self.skip_ws();
+                        unit = self.digits(10)?.parse::<usize>().ok().filter(|unit| (1..=256).contains(unit));
+                        if unit.is_none() {
+                            return self.error(spec_pos, String::from("unit should be 1..256"));
+                        }
+                    },
+                    "" => return self.unexpected("type specifier"),
+                    spec => return self.error(spec_pos, format!("unsupported type specifier '{}'", spec)),
+                }
+                if !self.eat("-") {
+                    break;
+                }
+            }
+        }
+        let seg_type = seg_type.unwrap_or(match value {
+            SegmentValue::Float(_) => SegmentType::Float,
+            _ => SegmentType::Integer,
+        });
+        let is_utf = matches!(seg_type, SegmentType::Utf8 | SegmentType::Utf16 | SegmentType::Utf32);
+        if is_utf && (size.is_some() || unit.is_some()) {
+            return self.error(start, String::from("utf segments can't have size or unit"));
+        }
+        let size = match size.unwrap_or(if seg_type == SegmentType::Float { 64 } else { 8 }).checked_mul(unit.unwrap_or(1)) {
+            Some(size) if size <= MAX_BINARY_BITS => size,
+            _ => return self.error(start, String::from("segment size is too large")),
+        };
+
+        let codes: Vec<BigInt> = match value {
+            SegmentValue::Str(ref s) => s.chars().map(|c| BigInt::from(c as u32)).collect(),
+            SegmentValue::Int(ref num) => vec!(num.clone()),
+            SegmentValue::Float(num) if seg_type == SegmentType::Float => {
+                return self.push_float(bits, num, size, little, start);
+            },
+            SegmentValue::Float(_) => return self.error(start, String::from("float value in non-float segment")),
+        };
+        let seg_bits = if is_utf { 32 } else { size };
+        if seg_bits.saturating_mul(codes.len()) > MAX_BINARY_BITS - bits.len {
+            return self.error(start, String::from("binary is larger than 16 MiB"));
+        }
+        for code in codes.iter() {
+            match seg_type {
+                SegmentType::Integer => bits.push_int(code, size, little),
+                SegmentType::Float => self.push_float(bits, code.to_f64().unwrap_or(f64::INFINITY), size, little, start)?,
+                _ => {
+                    let c = match code.to_u32().and_then(char::from_u32) {
+                        Some(c) => c,
+                        None => return self.error(start, String::from("bad character code for utf segment")),
+                    };
+                    match seg_type {
+                        SegmentType::Utf8 => {
+                            let mut buf = [0; 4];
+                            for byte in c.encode_utf8(&mut buf).bytes() {
+                                bits.push_int(&BigInt::from(byte), 8, false);
+                            }
+                        },
+                        SegmentType::Utf16 => {
+                            let mut buf = [0; 2];
+                            for unit in c.encode_utf16(&mut buf).iter() {
+                                bits.push_int(&BigInt::from(*unit), 16, little);
+                            }
+                        },
+                        _ => bits.push_int(code, 32, little),
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn push_float(&self, bits: &mut Bits, num: f64, size: usize, little: bool, start: usize) -> Result<(), Error> {
+        match size {
+            64 if num.is_finite() => bits.push_int(&BigInt::from(num.to_bits()), 64, little),
+            32 if (num as f32).is_finite() => bits.push_int(&BigInt::from((num as f32).to_bits()), 32, little),
+            64 | 32 => return self.error(start, String::from("float is out of range")),
+            _ => return self.error(start, String::from("float segment size should be 32 or 64")),
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn string_term(s: &str) -> Eterm {
+    if s.is_empty() {
+        return Eterm::Nil;
+    }
+    if s.chars().all(|c| (c as u32) < 256) {
+        return Eterm::String(s.chars().map(|c| c as u8).collect());
+    }
+    let mut elems: Vec<Eterm> = s.chars().map(|c| Eterm::from_i64(c as i64)).collect();
+    elems.push(Eterm::Nil);
+    Eterm::List(elems)
+}
+
+impl FromStr for Eterm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Eterm, Error> {
+        let mut parser = Parser{src: s, pos: 0, depth: 0};
+        let term = parser.term()?;
+        parser.eat(".");
+        parser.skip_ws();
+        if parser.peek().is_some() {
+            return parser.unexpected("end of input");
+        }
+        Ok(term)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::{Atom, Eterm, BitBinary, Error};
+    use std::str::FromStr;
+
+    fn atom(name: &str) -> Eterm {
+        Eterm::Atom(Atom::from(name))
+    }
+
+    fn parse(s: &str) -> Eterm {
+        match Eterm::from_str(s) {
+            Ok(term) => term,
+            Err(err) => panic!("{}: {}", s, err),
+        }
+    }
+
+    #[test]
+    fn parse_terms() {
+        assert_eq!(Eterm::Tuple(vec!(atom("ok"),
+                                     Eterm::Map(vec!((Eterm::Binary(b"a".to_vec()),
+                                                      Eterm::List(vec!(Eterm::SmallInteger(1), Eterm::SmallInteger(2),
+                                                                       Eterm::SmallInteger(3))))).into()))),
+                   parse("{ok, #{<<\"a\">> => [1,2|3]}}"));
+        assert_eq!(Eterm::List(vec!(atom("a"), atom("B c"), atom("d@e_1"), Eterm::Nil)),
+                   parse("['a', 'B c', d@e_1]."));
+        assert_eq!(Eterm::Tuple(vec!(Eterm::Integer(-255), Eterm::SmallInteger(255), Eterm::Integer(1000),
+                                     Eterm::SmallInteger(97), Eterm::SmallInteger(10), Eterm::from_i64(1 << 40))),
+                   parse("{-16#FF, 2#1111_1111, 1_000, $a, $\\n, 1099511627776}"));
+        assert_eq!(Eterm::from_str("123456789012345678901234567890").unwrap().to_string(),
+                   "123456789012345678901234567890");
+        assert_eq!(Eterm::List(vec!(Eterm::Float(1.5), Eterm::Float(-2.0e-3), Eterm::Float(1.0e20), Eterm::Nil)),
+                   parse("[1.5, - 2.0e-3, 1.0E+20]"));
+        assert_eq!(Eterm::String(b"ab\"c\x01".to_vec()), parse("\"ab\" % comment\n \"\\\"c\\^a\""));
+        assert_eq!(Eterm::List(vec!(Eterm::Integer(1087), Eterm::Nil)), parse("\"\\x{43F}\""));
+        assert_eq!(Eterm::Nil, parse("\"\""));
+        assert_eq!(Eterm::Map(vec!((atom("a"), Eterm::SmallInteger(2))).into()), parse("#{a => 1, a => 2}"));
+        assert_eq!(Eterm::Tuple(vec!()), parse("{ }"));
+        assert_eq!("fun lists:map/2", parse("fun lists:map/2").to_string());
+        // reads what Display writes
+        let term = parse("{'Quoted\\n', [0.1, 1.0e-10 | \"tail\"], #{<<1, 2:3>> => <<\"bin\">>}, \"\\x01\"}");
+        assert_eq!(term, parse(&term.to_string()));
+    }
+
+    #[test]
+    fn parse_binaries() {
+        assert_eq!(Eterm::Binary(vec!()), parse("<<>>"));
+        assert_eq!(Eterm::Binary(vec!(1, 2, 104, 105)), parse("<<1, 2, \"hi\">>"));
+        assert_eq!(Eterm::Binary(vec!(0, 1, 1, 0, 255)), parse("<<1:16, 1:16/little, -1>>"));
+        assert_eq!(Eterm::Binary(vec!(0xd0, 0xbf, 0, 0x41)), parse("<<\"\\x{43F}\"/utf8, $A/utf16>>"));
+        assert_eq!(Eterm::Binary(1.5f64.to_bits().to_be_bytes().to_vec()), parse("<<1.5/float>>"));
+        assert_eq!(Eterm::Binary(vec!(0x3f, 0x80, 0, 0)), parse("<<1:32/float>>"));
+        assert_eq!(Eterm::BitBinary(BitBinary{bits: 4, data: vec!(1, 0xF0)}), parse("<<1, 15:4>>"));
+        assert_eq!(Eterm::BitBinary(BitBinary{bits: 4, data: vec!(1, 0)}), parse("<<1:12/little>>"));
+        assert_eq!(Eterm::Binary(vec!(0, 0, 0, 5)), parse("<<5:4/unit:8>>"));
+        assert_eq!(Eterm::Binary(vec!(0xFF, 0xFF, 0xFE)), parse("<<-2:24>>"));
+        assert_eq!(Eterm::BitBinary(BitBinary{bits: 4, data: vec!(0xFF, 0xE0)}), parse("<<-2:12>>"));
+        assert_eq!(Eterm::Binary(vec!(0x34, 0x12)), parse("<<16#ABCD1234:16/little>>"));
+        let mut large = vec!(0; 1 << 20);
+        large[0] = 1;
+        assert_eq!(Eterm::Binary(large), parse("<<1:8388608/little>>"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = vec!(
+            ("{ok, ", 1, 6),
+            ("[1, 2", 1, 6),
+            ("{a b}", 1, 4),
+            ("\n  'abc", 2, 3),
+            ("<<1.5:8>>", 1, 3),
+            ("end", 1, 1),
+            ("37#1", 1, 1),
+            ("#{a}", 1, 4),
+            ("ok ok", 1, 4),
+            ("$\\x{110000}", 1, 2),
+            ("<<0:100000000000>>", 1, 3),
+            ("<<1, 0:8/unit:256, 0:16777216/unit:8>>", 1, 20),
+            ("<<\"abc\":134217727>>", 1, 3));
+        for (s, line, column) in cases {
+            match Eterm::from_str(s) {
+                Err(Error::Syntax(l, c, _)) => assert_eq!((line, column), (l, c), "{}", s),
+                other => panic!("{}: {:?}", s, other),
+            }
+        }
+        let deep = format!("{}{}", "[".repeat(512), "]".repeat(512));
+        assert!(Eterm::from_str(&deep).is_ok());
+        match Eterm::from_str(&format!("[{}]", deep)) {
+            Err(Error::Syntax(1, 513, _)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,867 @@
+// Framing of Erlang port I/O, see `erlang:open_port/2` options `{packet, N}`,
+// `{line, L}` and `stream`.
+//
+// Port program reads requests from stdin and writes responses to stdout;
+// `PortReader` and `PortWriter` wrap those streams and take care of packet
+// headers, the 131 prelude and `Decoder` / `Encoder` setup.
+//
+// `{line, L}` ports carry text, not terms: any encoded term may contain byte
+// `\n`, so `read_term` / `write_term` refuse to work in line mode; use
+// `read_line` and `write_frame` instead.
+
+use std::io;
+use std::io::{Read, Write};
+use std::error;
+use std::borrow::Borrow;
+use std::sync::{atomic, mpsc, Arc, Mutex};
+use std::sync::atomic::AtomicBool;
+use std::panic;
+use std::thread;
+use std::process;
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use super::{Atom, Decoder, DecoderOptions, Encoder, EncoderOptions, Eterm, Error};
+
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Packet {
+    One,                        // {packet, 1}
+    Two,                        // {packet, 2}
+    Four,                       // {packet, 4}
+    Line(usize),                // {line, L}; longer lines are read as several chunks
+    Stream,                     // stream, no framing at all
+}
+
+impl Packet {
+    // max payload size, which fits into packet header
+    pub fn max_size(&self) -> usize {
+        match *self {
+            Packet::One => u8::MAX as usize,
+            Packet::Two => u16::MAX as usize,
+            Packet::Four => u32::MAX as usize,
+            Packet::Line(_) | Packet::Stream => usize::MAX,
+        }
+    }
+
+    fn header_len(&self) -> usize {
+        match *self {
+            Packet::One => 1,
+            Packet::Two => 2,
+            Packet::Four => 4,
+            Packet::Line(_) | Packet::Stream => 0,
+        }
+    }
+
+    fn write_header<W: Write>(&self, wrtr: &mut W, len: usize) -> Result<(), Error> {
+        let max_size = self.max_size();
+        if len > max_size {
+            return Err(Error::FrameTooLarge(len, max_size));
+        }
+        match *self {
+            Packet::One => wrtr.write_u8(len as u8)?,
+            Packet::Two => wrtr.write_u16::<BigEndian>(len as u16)?,
+            Packet::Four => wrtr.write_u32::<BigEndian>(len as u32)?,
+            Packet::Line(_) | Packet::Stream => (),
+        };
+        Ok(())
+    }
+}
+
+// Default limit of incoming frame size: header of `{packet, 4}` may claim up
+// to 4 GiB, which shouldn't be trusted blindly
+pub const DEFAULT_MAX_FRAME: usize = 64 * 1024 * 1024;
+
+// Line of `{line, L}` port, like Erlang delivers it: a line longer than L bytes
+// comes as `{noeol, Chunk}` parts followed by `{eol, Rest}`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Line {
+    Eol(Vec<u8>),               // {eol, Line}, without "\n"
+    NoEol(Vec<u8>),             // {noeol, Chunk}; also the last line without "\n"
+}
+
+fn line_mode_error() -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                             "{line, L} port can't carry external term format"))
+}
+
+
+pub struct PortReader<R> {
+    rdr: R,
+    packet: Packet,
+    options: DecoderOptions,
+    max_frame: usize,
+    peeked: Option<u8>,         // byte after full `{line, L}` chunk
+}
+
+impl<R: Read> PortReader<R> {
+    pub fn new(rdr: R, packet: Packet) -> PortReader<R> {
+        PortReader::with_options(rdr, packet, DecoderOptions::default())
+    }
+
+    pub fn with_options(rdr: R, packet: Packet, options: DecoderOptions) -> PortReader<R> {
+        PortReader{rdr, packet, options, max_frame: DEFAULT_MAX_FRAME, peeked: None}
+    }
+
+    // Larger frames are rejected with `FrameTooLarge` before they're read
+    pub fn max_frame_size(mut self, size: usize) -> PortReader<R> {
+        self.max_frame = size;
+        self
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.rdr
+    }
+
+    pub fn into_inner(self) -> R {
+        self.rdr
+    }
+
+    // Returns first byte of the next frame or None if port was closed
+    // between frames.
+    fn _read_first_byte(&mut self) -> Result<Option<u8>, Error> {
+        if let Some(byte) = self.peeked.take() {
+            return Ok(Some(byte));
+        }
+        let mut buf = [0u8; 1];
+        loop {
+            match self.rdr.read(&mut buf) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(buf[0])),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(Error::Io(e)),
+            }
+        }
+    }
+
+    // Buffer grows as data arrives, so a lying header doesn't allocate
+    fn _read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        if len > self.max_frame {
+            return Err(Error::FrameTooLarge(len, self.max_frame));
+        }
+        let mut buf = Vec::new();
+        self.rdr.by_ref().take(len as u64).read_to_end(&mut buf)?;
+        if buf.len() < len {
+            return Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                "port closed inside of frame")));
+        }
+        Ok(buf)
+    }
+
+    // Reads next line of `{line, L}` port. Like erts does, a chunk of L bytes
+    // followed by "\n" is a complete line. Returns Ok(None) when port was
+    // closed.
+    pub fn read_line(&mut self) -> Result<Option<Line>, Error> {
+        let max_len = match self.packet {
+            Packet::Line(len) => len,
+            _ => return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                     "port doesn't use {line, L} framing"))),
+        };
+        let mut line = Vec::new();
+        loop {
+            match self._read_first_byte()? {
+                None if line.is_empty() => return Ok(None),
+                None => return Ok(Some(Line::NoEol(line))),
+                Some(b'\n') => return Ok(Some(Line::Eol(line))),
+                Some(byte) if line.len() >= max_len.max(1) => {
+                    self.peeked = Some(byte);
+                    return Ok(Some(Line::NoEol(line)));
+                },
+                Some(byte) => line.push(byte),
+            }
+        }
+    }
+
+    // Reads next frame payload (without header or line terminator); line
+    // chunks are returned without eol flag, see `read_line`.
+    // In stream mode there are no frames, so whatever is available is returned.
+    // Returns Ok(None) when the other side closed the port.
+    pub fn read_frame(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        if let Packet::Line(_) = self.packet {
+            return Ok(self.read_line()?.map(|line| match line {
+                Line::Eol(data) | Line::NoEol(data) => data,
+            }));
+        }
+        let first = match self._read_first_byte()? {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let frame = match self.packet {
+            Packet::One =>
+                self._read_exact(first as usize)?,
+            Packet::Two => {
+                let len = ((first as usize) << 8) | (self.rdr.read_u8()? as usize);
+                self._read_exact(len)?
+            },
+            Packet::Four => {
+                let rest = self.rdr.read_u24::<BigEndian>()? as usize;
+                self._read_exact(((first as usize) << 24) | rest)?
+            },
+            Packet::Line(_) => unreachable!(),
+            Packet::Stream => {
+                let mut buf = vec![0u8; 4096];
+                buf[0] = first;
+                let n = self.rdr.read(&mut buf[1..])?;
+                buf.truncate(n + 1);
+                buf
+            },
+        };
+        Ok(Some(frame))
+    }
+
+    // Reads and decodes next term. Returns Ok(None) when port was closed.
+    pub fn read_term(&mut self) -> Result<Option<Eterm>, Error> {
+        if let Packet::Line(_) = self.packet {
+            return Err(line_mode_error());
+        }
+        if self.packet == Packet::Stream {
+            // term is self-delimiting, so decode it right from the stream
+            match self._read_first_byte()? {
+                None => Ok(None),
+                Some(131) => {
+                    let mut decoder = Decoder::with_options(&mut self.rdr, self.options.clone());
+                    Ok(Some(decoder.decode_term()?))
+                },
+                Some(other) => Err(Error::BadPrelude(other)),
+            }
+        } else {
+            match self.read_frame()? {
+                None => Ok(None),
+                Some(frame) => {
+                    let mut rdr = io::Cursor::new(frame);
+                    let prelude = rdr.read_u8()?;
+                    if prelude != 131 {
+                        return Err(Error::BadPrelude(prelude));
+                    }
+                    let mut decoder = Decoder::with_options(&mut rdr, self.options.clone());
+                    Ok(Some(decoder.decode_term()?))
+                },
+            }
+        }
+    }
+}
+
+
+pub struct PortWriter<W: Write> {
+    wrtr: W,
+    packet: Packet,
+    options: EncoderOptions,
+}
+
+impl<W: Write> PortWriter<W> {
+    pub fn new(wrtr: W, packet: Packet, options: EncoderOptions) -> PortWriter<W> {
+        PortWriter{wrtr, packet, options}
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.wrtr
+    }
+
+    pub fn into_inner(self) -> W {
+        self.wrtr
+    }
+
+    // Writes single frame (header + payload or payload + "\n") and flushes it.
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        if let Packet::Line(_) = self.packet {
+            if data.contains(&b'\n') {
+                return Err(Error::Io(io::Error::new(io::ErrorKind::InvalidInput,
+                                                    "line frame contains newline")));
+            }
+        }
+        self.packet.write_header(&mut self.wrtr, data.len())?;
+        self.wrtr.write_all(data)?;
+        if let Packet::Line(_) = self.packet {
+            self.wrtr.write_u8(b'\n')?;
+        }
+        self.wrtr.flush().map_err(From::from)
+    }
+
+    // Encodes term with 131 prelude and writes it as a single frame. Frame
+    // is built in memory first, so unbuffered writers get one write per term.
+    pub fn write_term<T: Borrow<Eterm>>(&mut self, term: T) -> Result<(), Error> {
+        if let Packet::Line(_) = self.packet {
+            return Err(line_mode_error());
+        }
+        let header_len = self.packet.header_len();
+        let mut frame = {
+            let mut encoder = Encoder::with_vec(vec!(0; header_len), self.options.clone());
+            encoder.write_prelude()?;
+            encoder.encode_term(term.borrow())?;
+            encoder.into_inner()
+        };
+        let len = frame.len() - header_len;
+        self.packet.write_header(&mut &mut frame[..header_len], len)?;
+        self.wrtr.write_all(&frame)?;
+        self.wrtr.flush().map_err(From::from)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.wrtr.flush()
+    }
+}
+
+
+pub type HandlerResult = Result<Eterm, Box<dyn error::Error + Send + Sync>>;
+
+// Request handler of port program. Ok(Reply) is sent back as is, while
+// Err(err) is sent as `{error, <<"err's Display">>}`.
+pub trait Handler {
+    fn handle(&mut self, request: Eterm) -> HandlerResult;
+}
+
+impl<F> Handler for F where F: FnMut(Eterm) -> HandlerResult {
+    fn handle(&mut self, request: Eterm) -> HandlerResult {
+        self(request)
+    }
+}
+
+
+// Request-response loop of port program: reads a request, passes it to the
+// handler and writes handler's reply back, until stdin is closed.
+pub struct PortServer<H: Handler> {
+    handler: H,
+    packet: Packet,
+    options: EncoderOptions,
+    max_frame: usize,
+}
+
+impl<H: Handler> PortServer<H> {
+    pub fn new(handler: H, packet: Packet, options: EncoderOptions) -> PortServer<H> {
+        PortServer{handler, packet, options, max_frame: DEFAULT_MAX_FRAME}
+    }
+
+    // See `PortReader::max_frame_size`
+    pub fn max_frame_size(mut self, size: usize) -> PortServer<H> {
+        self.max_frame = size;
+        self
+    }
+
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    // Serves requests from stdin to stdout. Returns Ok(()) when port is closed.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        self.run_with(stdin.lock(), stdout.lock())
+    }
+
+    pub fn run_with<R: Read, W: Write>(&mut self, rdr: R, wrtr: W) -> Result<(), Error> {
+        let mut reader = PortReader::new(rdr, self.packet).max_frame_size(self.max_frame);
+        let mut writer = PortWriter::new(wrtr, self.packet, self.options.clone());
+        while let Some(request) = reader.read_term()? {
+            let reply = match self.handler.handle(request) {
+                Ok(reply) => reply,
+                Err(err) => error_reply(err),
+            };
+            writer.write_term(reply)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Port server, which handles `{Ref, Request}` messages concurrently on a pool
+// of worker threads and replies with `{Ref, Reply}` in completion order.
+// Other messages aren't replied; they are dropped and logged to stderr.
+// Each worker owns a clone of the handler; all replies go through a single
+// writer thread, so frames never interleave.
+pub struct ConcurrentPortServer<H: Handler + Clone + Send> {
+    handler: H,
+    packet: Packet,
+    workers: usize,
+    options: EncoderOptions,
+    max_frame: usize,
+}
+
+impl<H: Handler + Clone + Send> ConcurrentPortServer<H> {
+    pub fn new(handler: H, packet: Packet, workers: usize,
+               options: EncoderOptions) -> ConcurrentPortServer<H> {
+        assert!(workers > 0, "at least one worker thread is required");
+        ConcurrentPortServer{handler, packet, workers, options, max_frame: DEFAULT_MAX_FRAME}
+    }
+
+    // See `PortReader::max_frame_size`
+    pub fn max_frame_size(mut self, size: usize) -> ConcurrentPortServer<H> {
+        self.max_frame = size;
+        self
+    }
+
+    // Serves requests from stdin to stdout. Returns Ok(()) when port is closed
+    // and all pending requests are replied.
+    pub fn run(&mut self) -> Result<(), Error> {
+        let stdin = io::stdin();
+        self.run_with(stdin.lock(), io::stdout())
+    }
+
+    pub fn run_with<R: Read, W: Write + Send>(&mut self, rdr: R, wrtr: W) -> Result<(), Error> {
+        let mut reader = PortReader::new(rdr, self.packet).max_frame_size(self.max_frame);
+        let mut writer = PortWriter::new(wrtr, self.packet, self.options.clone());
+        let (job_tx, job_rx) = mpsc::sync_channel::<(Eterm, Eterm)>(self.workers * 2);
+        // workers own the receiver, so sends fail as soon as the last one exits
+        let job_rx = Arc::new(Mutex::new(job_rx));
+        let (reply_tx, reply_rx) = mpsc::channel::<Eterm>();
+        let write_failed = AtomicBool::new(false);
+
+        thread::scope(|scope| {
+            let write_failed = &write_failed;
+            let writer_thread = scope.spawn(move || -> Result<(), Error> {
+                for reply in reply_rx {
+                    if let Err(err) = writer.write_term(reply) {
+                        write_failed.store(true, atomic::Ordering::SeqCst);
+                        return Err(err);
+                    }
+                }
+                Ok(())
+            });
+            for _ in 0..self.workers {
+                let mut handler = self.handler.clone();
+                let reply_tx = reply_tx.clone();
+                let job_rx = job_rx.clone();
+                scope.spawn(move || {
+                    loop {
+                        // lock is released as soon as the job is taken; a
+                        // poisoned lock still guards a valid receiver
+                        let job = job_rx.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                        let (tag, request) = match job {
+                            Ok(job) => job,
+                            Err(_) => break, // reader is done
+                        };
+                        let reply = match handle_caught(&mut handler, request) {
+                            Ok(reply) => reply,
+                            Err(err) => error_reply(err),
+                        };
+                        if reply_tx.send(Eterm::Tuple(vec!(tag, reply))).is_err() {
+                            break;      // writer failed
+                        }
+                    }
+                });
+            }
+            drop(job_rx);
+
+            let read_result = (|| -> Result<(), Error> {
+                // writer may fail while read blocks, so both sides of it are checked
+                while !write_failed.load(atomic::Ordering::SeqCst) {
+                    let message = match reader.read_term()? {
+                        Some(message) => message,
+                        None => break,
+                    };
+                    if write_failed.load(atomic::Ordering::SeqCst) {
+                        break;
+                    }
+                    match message {
+                        Eterm::Tuple(mut pair) if pair.len() == 2 => {
+                            let request = pair.pop().unwrap();
+                            let tag = pair.pop().unwrap();
+                            if job_tx.send((tag, request)).is_err() {
+                                break;  // all workers are gone
+                            }
+                        },
+                        // there is no Ref to reply with, and an untagged reply
+                        // would reach nobody
+                        other =>
+                            eprintln!("erl_ext: dropped message, expected {{Ref, Request}}: {}", other),
+                    }
+                }
+                Ok(())
+            })();
+            // let workers drain the queue and the writer drain replies
+            drop(job_tx);
+            drop(reply_tx);
+            // writer's error is the cause of a stopped read, so it goes first
+            let write_result = writer_thread.join().unwrap();
+            write_result.and(read_result)
+        })
+    }
+}
+
+// Panic of handler becomes an error reply instead of killing its worker
+fn handle_caught<H: Handler>(handler: &mut H, request: Eterm) -> HandlerResult {
+    match panic::catch_unwind(panic::AssertUnwindSafe(|| handler.handle(request))) {
+        Ok(result) => result,
+        Err(payload) => {
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(msg) => msg.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(msg) => msg.clone(),
+                    None => "unknown".to_string(),
+                },
+            };
+            Err(format!("handler panicked: {}", msg).into())
+        },
+    }
+}
+
+fn error_reply(err: Box<dyn error::Error + Send + Sync>) -> Eterm {
+    Eterm::Tuple(vec!(Eterm::Atom(Atom::from("error")),
+                      Eterm::Binary(err.to_string().into_bytes())))
+}
+
+
+// Erlang side of `open_port({spawn_executable, ...}, [{packet, N}, binary])`:
+// spawns port program and talks to it through its stdin and stdout.
+// Child's stderr is inherited.
+pub struct PortClient {
+    child: process::Child,
+    reader: PortReader<process::ChildStdout>,
+    writer: Option<PortWriter<process::ChildStdin>>,
+}
+
+impl PortClient {
+    pub fn spawn(command: &mut process::Command, packet: Packet,
+                 options: EncoderOptions) -> Result<PortClient, Error> {
+        let mut child = command
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        Ok(PortClient{
+            child,
+            reader: PortReader::new(stdout, packet),
+            writer: Some(PortWriter::new(stdin, packet, options)),
+        })
+    }
+
+    pub fn id(&self) -> u32 {
+        self.child.id()
+    }
+
+    // Like `Port ! {self(), {command, term_to_binary(Term)}}`
+    pub fn send<T: Borrow<Eterm>>(&mut self, term: T) -> Result<(), Error> {
+        match self.writer {
+            Some(ref mut writer) => writer.write_term(term),
+            None => Err(Error::Io(io::Error::new(io::ErrorKind::BrokenPipe,
+                                                 "port input is closed"))),
+        }
+    }
+
+    // Receives next reply; Ok(None) means port program closed its stdout.
+    pub fn recv(&mut self) -> Result<Option<Eterm>, Error> {
+        self.reader.read_term()
+    }
+
+    // Sends request and waits for a single reply.
+    pub fn call<T: Borrow<Eterm>>(&mut self, term: T) -> Result<Eterm, Error> {
+        self.send(term)?;
+        match self.recv()? {
+            Some(reply) => Ok(reply),
+            None => Err(Error::Io(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                                 "port closed before reply"))),
+        }
+    }
+
+    // Closes port program's stdin (so it sees port closed) without waiting.
+    pub fn close_input(&mut self) {
+        self.writer = None;
+    }
+
+    // Closes port program's stdin and waits for it to exit.
+    pub fn close(mut self) -> Result<process::ExitStatus, Error> {
+        self.close_input();
+        self.child.wait().map_err(From::from)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::{Packet, Line, PortReader, PortWriter, PortServer, ConcurrentPortServer, HandlerResult, PortClient};
+    use super::super::{Atom, Eterm, EncoderOptions, Error};
+    use std::io;
+    use std::thread;
+    use std::sync::mpsc;
+    use std::time::Duration;
+    use std::process::Command;
+
+    fn roundtrip(packet: Packet, term: Eterm) {
+        let mut out = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut out, packet, EncoderOptions::new());
+            writer.write_term(&term).unwrap();
+            writer.write_term(Eterm::Nil).unwrap();
+        }
+        let mut reader = PortReader::new(io::Cursor::new(out), packet);
+        assert_eq!(Some(term), reader.read_term().unwrap());
+        assert_eq!(Some(Eterm::Nil), reader.read_term().unwrap());
+        assert_eq!(None, reader.read_term().unwrap());
+    }
+
+    #[test]
+    fn packet_roundtrip() {
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Binary(vec!(1, 2, 3))));
+        roundtrip(Packet::One, term.clone());
+        roundtrip(Packet::Two, term.clone());
+        roundtrip(Packet::Four, term.clone());
+        roundtrip(Packet::Stream, term);
+    }
+
+    #[test]
+    fn packet_header() {
+        let mut out = Vec::new();
+        PortWriter::new(&mut out, Packet::Two, EncoderOptions::new())
+            .write_frame(b"abc").unwrap();
+        assert_eq!(vec!(0, 3, b'a', b'b', b'c'), out);
+        out.clear();
+        PortWriter::new(&mut out, Packet::Four, EncoderOptions::new())
+            .write_frame(b"abc").unwrap();
+        assert_eq!(vec!(0, 0, 0, 3, b'a', b'b', b'c'), out);
+    }
+
+    // Counts write calls, like syscalls of unbuffered ChildStdin
+    struct CountingWriter(Vec<u8>, usize);
+
+    impl io::Write for CountingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.1 += 1;
+            self.0.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn packet_single_write() {
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")),
+                                     Eterm::Binary(vec!(1, 2, 3))));
+        let mut writer = PortWriter::new(CountingWriter(Vec::new(), 0), Packet::Two, EncoderOptions::new());
+        writer.write_term(&term).unwrap();
+        assert_eq!(1, writer.get_ref().1);
+        let mut reader = PortReader::new(io::Cursor::new(writer.into_inner().0), Packet::Two);
+        assert_eq!(Some(term), reader.read_term().unwrap());
+    }
+
+    #[test]
+    fn packet_too_large() {
+        let mut out = Vec::new();
+        let mut writer = PortWriter::new(&mut out, Packet::One, EncoderOptions::new());
+        match writer.write_term(Eterm::Binary(vec!(0; 255))) {
+            Err(Error::FrameTooLarge(261, 255)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert!(writer.get_ref().is_empty());
+    }
+
+    #[test]
+    fn packet_line() {
+        let mut out = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut out, Packet::Line(5), EncoderOptions::new());
+            writer.write_frame(b"hello").unwrap();
+            writer.write_frame(b"").unwrap();
+            assert!(writer.write_frame(b"a\nb").is_err());
+            // terms may contain "\n"
+            assert!(writer.write_term(Eterm::Nil).is_err());
+        }
+        assert_eq!(b"hello\n\n".to_vec(), out);
+        out.extend_from_slice(b"too long line\nend");
+        let mut reader = PortReader::new(io::Cursor::new(out), Packet::Line(5));
+        assert_eq!(Some(Line::Eol(b"hello".to_vec())), reader.read_line().unwrap());
+        assert_eq!(Some(vec!()), reader.read_frame().unwrap());
+        // like {noeol, "too l"}, {noeol, "ong l"}, {eol, "ine"}
+        assert_eq!(Some(Line::NoEol(b"too l".to_vec())), reader.read_line().unwrap());
+        assert_eq!(Some(Line::NoEol(b"ong l".to_vec())), reader.read_line().unwrap());
+        assert_eq!(Some(Line::Eol(b"ine".to_vec())), reader.read_line().unwrap());
+        assert_eq!(Some(Line::NoEol(b"end".to_vec())), reader.read_line().unwrap());
+        assert_eq!(None, reader.read_line().unwrap());
+        assert!(reader.read_term().is_err());
+    }
+
+    #[test]
+    fn packet_max_frame() {
+        // header claims 4 GiB - 1, but nothing follows
+        let input = vec!(255, 255, 255, 255, 1);
+        let mut reader = PortReader::new(io::Cursor::new(input.clone()), Packet::Four);
+        match reader.read_frame() {
+            Err(Error::FrameTooLarge(4294967295, 67108864)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        let mut reader = PortReader::new(io::Cursor::new(input), Packet::Four)
+            .max_frame_size(usize::MAX);
+        assert!(reader.read_frame().is_err());
+        let mut reader = PortReader::new(io::Cursor::new(vec!(0, 3, 1, 2, 3)), Packet::Two)
+            .max_frame_size(2);
+        match reader.read_frame() {
+            Err(Error::FrameTooLarge(3, 2)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn packet_truncated() {
+        let mut reader = PortReader::new(io::Cursor::new(vec!(0, 5, 1, 2)), Packet::Two);
+        assert!(reader.read_frame().is_err());
+    }
+
+    #[test]
+    fn server_loop() {
+        let mut input = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut input, Packet::Four, EncoderOptions::new());
+            writer.write_term(Eterm::SmallInteger(1)).unwrap();
+            writer.write_term(Eterm::Nil).unwrap();
+        }
+        let mut output = Vec::new();
+        let mut calls = 0;
+        {
+            let handler = |req: Eterm| {
+                calls += 1;
+                match req {
+                    Eterm::SmallInteger(n) => Ok(Eterm::SmallInteger(n + 1)),
+                    _ => Err("badarg".into()),
+                }
+            };
+            let mut server = PortServer::new(handler, Packet::Four, EncoderOptions::new());
+            server.run_with(io::Cursor::new(input), &mut output).unwrap();
+        }
+        assert_eq!(2, calls);
+        let mut reader = PortReader::new(io::Cursor::new(output), Packet::Four);
+        assert_eq!(Some(Eterm::SmallInteger(2)), reader.read_term().unwrap());
+        assert_eq!(Some(Eterm::Tuple(vec!(Eterm::Atom(Atom::from("error")),
+                                          Eterm::Binary(b"badarg".to_vec())))),
+                   reader.read_term().unwrap());
+        assert_eq!(None, reader.read_term().unwrap());
+    }
+
+    #[test]
+    fn concurrent_server() {
+        let mut input = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut input, Packet::Four, EncoderOptions::new());
+            // {Ref, SleepMs}
+            writer.write_term(Eterm::Tuple(vec!(Eterm::SmallInteger(1),
+                                                Eterm::SmallInteger(200)))).unwrap();
+            writer.write_term(Eterm::Tuple(vec!(Eterm::SmallInteger(2),
+                                                Eterm::SmallInteger(0)))).unwrap();
+            writer.write_term(Eterm::Tuple(vec!(Eterm::SmallInteger(3),
+                                                Eterm::Nil))).unwrap();
+        }
+        let handler = |req: Eterm| -> HandlerResult {
+            match req {
+                Eterm::SmallInteger(ms) => {
+                    thread::sleep(Duration::from_millis(ms as u64));
+                    Ok(Eterm::Atom(Atom::from("done")))
+                },
+                _ => Err("badarg".into()),
+            }
+        };
+        let mut output = Vec::new();
+        ConcurrentPortServer::new(handler, Packet::Four, 2, EncoderOptions::new())
+            .run_with(io::Cursor::new(input), &mut output).unwrap();
+
+        let mut reader = PortReader::new(io::Cursor::new(output), Packet::Four);
+        let mut replies = Vec::new();
+        while let Some(reply) = reader.read_term().unwrap() {
+            replies.push(reply);
+        }
+        let done = Eterm::Atom(Atom::from("done"));
+        let error = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("error")),
+                                      Eterm::Binary(b"badarg".to_vec())));
+        assert_eq!(3, replies.len());
+        // slow request is replied last
+        assert_eq!(Eterm::Tuple(vec!(Eterm::SmallInteger(1), done.clone())), replies[2]);
+        assert!(replies.contains(&Eterm::Tuple(vec!(Eterm::SmallInteger(2), done))));
+        assert!(replies.contains(&Eterm::Tuple(vec!(Eterm::SmallInteger(3), error))));
+    }
+
+    #[test]
+    fn concurrent_server_untagged() {
+        let mut input = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut input, Packet::Four, EncoderOptions::new());
+            writer.write_term(Eterm::Atom(Atom::from("oops"))).unwrap();
+            writer.write_term(Eterm::Tuple(vec!(Eterm::SmallInteger(1), Eterm::Nil))).unwrap();
+        }
+        let handler = |req: Eterm| -> HandlerResult { Ok(req) };
+        let mut output = Vec::new();
+        ConcurrentPortServer::new(handler, Packet::Four, 2, EncoderOptions::new())
+            .run_with(io::Cursor::new(input), &mut output).unwrap();
+        let mut reader = PortReader::new(io::Cursor::new(output), Packet::Four);
+        assert_eq!(Some(Eterm::Tuple(vec!(Eterm::SmallInteger(1), Eterm::Nil))),
+                   reader.read_term().unwrap());
+        assert_eq!(None, reader.read_term().unwrap());
+    }
+
+    struct FailingWriter;
+
+    impl io::Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"))
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn concurrent_server_write_error() {
+        // many more requests than the job queue holds
+        let mut input = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut input, Packet::Four, EncoderOptions::new());
+            for i in 0..100 {
+                writer.write_term(Eterm::Tuple(vec!(Eterm::from_i64(i), Eterm::Nil))).unwrap();
+            }
+        }
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let handler = |req: Eterm| -> HandlerResult { Ok(req) };
+            let result = ConcurrentPortServer::new(handler, Packet::Four, 2, EncoderOptions::new())
+                .run_with(io::Cursor::new(input), FailingWriter);
+            done_tx.send(result).unwrap();
+        });
+        match done_rx.recv_timeout(Duration::from_secs(10)) {
+            Ok(Err(Error::Io(ref err))) if err.kind() == io::ErrorKind::BrokenPipe => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn concurrent_server_handler_panic() {
+        let mut input = Vec::new();
+        {
+            let mut writer = PortWriter::new(&mut input, Packet::Four, EncoderOptions::new());
+            for i in 0..10 {
+                writer.write_term(Eterm::Tuple(vec!(Eterm::from_i64(i), Eterm::from_i64(i)))).unwrap();
+            }
+        }
+        let handler = |req: Eterm| -> HandlerResult {
+            if req == Eterm::SmallInteger(3) {
+                panic!("boom");
+            }
+            Ok(req)
+        };
+        let mut output = Vec::new();
+        ConcurrentPortServer::new(handler, Packet::Four, 2, EncoderOptions::new())
+            .run_with(io::Cursor::new(input), &mut output).unwrap();
+        let mut reader = PortReader::new(io::Cursor::new(output), Packet::Four);
+        let mut replies = Vec::new();
+        while let Some(reply) = reader.read_term().unwrap() {
+            replies.push(reply);
+        }
+        assert_eq!(10, replies.len());
+        let error = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("error")),
+                                      Eterm::Binary(b"handler panicked: boom".to_vec())));
+        assert!(replies.contains(&Eterm::Tuple(vec!(Eterm::SmallInteger(3), error))));
+    }
+
+    #[test]
+    fn client_echo() {
+        // `cat` is a perfect echo port program for any framing
+        let mut client = PortClient::spawn(&mut Command::new("cat"), Packet::Two, EncoderOptions::new())
+            .expect("`cat` is required for this test");
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("hello")),
+                                     Eterm::Binary(vec!(0; 1000))));
+        assert_eq!(term, client.call(&term).unwrap());
+        client.send(Eterm::Nil).unwrap();
+        client.close_input();
+        assert!(client.send(Eterm::Nil).is_err());
+        assert_eq!(Some(Eterm::Nil), client.recv().unwrap());
+        assert_eq!(None, client.recv().unwrap());
+        assert!(client.close().unwrap().success());
+    }
+}
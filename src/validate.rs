@@ -0,0 +1,697 @@
+// Validation-only decoding: walks encoded term and checks its structure
+// without building `Eterm`, collecting some statistics on the way. It fails
+// on the same input as decoder with the same options does, except that atoms
+// aren't interned, so a full `AtomTable` isn't reported.
+//
+// `check_term` does the opposite: checks that `Eterm` can be encoded with
+// given options. Encoder runs the same checks on its way.
+
+use std::io;
+use std::io::Read;
+use std::str;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use super::{Decoder, ErlMap, ErlTermTag, Error, Eterm, EncoderOptions, IdentifierTags};
+
+
+#[derive(Debug, Clone)]
+pub struct TermStats {
+    tag_counts: [usize; 256],
+    pub terms: usize,           // total number of (sub)terms
+    pub max_depth: usize,       // top-level term has depth 1
+    pub largest_binary: usize,  // payload size of the largest binary / bit-binary
+    pub atom_bytes: usize,      // total size of atom texts, including node names
+    pub canonical: bool,        // whether tags are the ones `term_to_binary/1` of OTP 26+ uses
+}
+
+impl TermStats {
+    fn new() -> TermStats {
+        TermStats{
+            tag_counts: [0; 256],
+            terms: 0,
+            max_depth: 0,
+            largest_binary: 0,
+            atom_bytes: 0,
+            canonical: true,
+        }
+    }
+
+    pub fn tag_count(&self, tag: ErlTermTag) -> usize {
+        self.tag_counts[tag as usize]
+    }
+}
+
+
+impl<'a, T> Decoder<'a, T> where T: io::Read + 'a {
+    // Checks next term and returns its statistics. Nothing is allocated
+    // except the statistics itself and map keys, which are decoded (without
+    // atom table) to find duplicates.
+    pub fn validate_term(&mut self) -> Result<TermStats, Error> {
+        let mut stats = TermStats::new();
+        self._skip_term(&mut stats, 1)?;
+        Ok(stats)
+    }
+
+    fn _skip(&mut self, len: u64) -> Result<(), Error> {
+        let copied = io::copy(&mut self.rdr.take(len), &mut io::sink())?;
+        if copied < len {
+            return Err(Error::ByteorderUnexpectedEOF);
+        }
+        Ok(())
+    }
+
+    fn _skip_term(&mut self, stats: &mut TermStats, depth: usize) -> Result<ErlTermTag, Error> {
+        let tag = self._decode_tag()?;
+        if depth > self.options.max_depth {
+            return Err(Error::TooDeep(self.options.max_depth));
+        }
+        self._skip_concrete_term(tag, stats, depth)?;
+        Ok(tag)
+    }
+
+    fn _skip_some(&mut self, stats: &mut TermStats, depth: usize,
+                  allowed: &[ErlTermTag]) -> Result<ErlTermTag, Error> {
+        let tag = self._decode_tag()?;
+        if !allowed.contains(&tag) {
+            return Err(Error::UnexpectedTerm(tag));
+        }
+        self._skip_concrete_term(tag, stats, depth)?;
+        Ok(tag)
+    }
+
+    fn _skip_any_atom(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        self._skip_some(stats, depth, &[ErlTermTag::ATOM_EXT, ErlTermTag::ATOM_UTF8_EXT,
+                                        ErlTermTag::SMALL_ATOM_EXT, ErlTermTag::SMALL_ATOM_UTF8_EXT])
+            .map(|_| ())
+    }
+
+    fn _skip_atom_text(&mut self, stats: &mut TermStats, len: usize, latin1: bool) -> Result<(), Error> {
+        stats.atom_bytes += len;
+        self._read_atom_text(len, latin1)
+    }
+
+    fn _skip_compressed(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        let mut rdr = io::Cursor::new(self._read_compressed()?);
+        Decoder::with_options(&mut rdr, self.options.clone())._skip_term(stats, depth).map(|_| ())
+    }
+
+    fn _skip_map(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        let arity = self.rdr.read_u32::<BigEndian>()?;
+        let mut keys = Vec::new();
+        for _ in 0..arity {
+            keys.push((self._skip_map_key(stats, depth + 1)?, Eterm::Nil));
+            self._skip_term(stats, depth + 1)?;
+        }
+        ErlMap::from_unique(keys).map(|_| ()).map_err(Error::DuplicateKey)
+    }
+
+    // Key is validated like any other term, then decoded from its bytes.
+    // Keys are compared by value (1 as SMALL_INTEGER_EXT and INTEGER_EXT is
+    // the same key), so raw bytes won't do. Atoms of the key aren't put into
+    // `AtomTable`, so validation doesn't use up its limit.
+    fn _skip_map_key(&mut self, stats: &mut TermStats, depth: usize) -> Result<Eterm, Error> {
+        let mut recorder = Recorder{inner: &mut *self.rdr as &mut dyn io::Read, bytes: Vec::new()};
+        {
+            let mut decoder = Decoder::with_options(&mut recorder, self.options.clone());
+            decoder._skip_term(stats, depth)?;
+        }
+        let mut rdr = io::Cursor::new(recorder.bytes);
+        let options = self.options.clone().atom_table(None);
+        Decoder::with_options(&mut rdr, options).decode_term()
+    }
+
+    fn _skip_big(&mut self, stats: &mut TermStats, n: usize) -> Result<(), Error> {
+        let sign = self.rdr.read_u8()?;
+        // little-endian digits, so only the last non-zero bytes matter
+        let mut buf = [0u8; 8];
+        let mut significant = 0;
+        let mut low = 0u64;
+        for i in 0..n {
+            let byte = self.rdr.read_u8()?;
+            if i < buf.len() {
+                buf[i] = byte;
+            }
+            if byte != 0 {
+                significant = i + 1;
+            }
+        }
+        for (i, byte) in buf.iter().enumerate().take(significant.min(8)) {
+            low |= (*byte as u64) << (8 * i);
+        }
+        let fits_integer = significant <= 4 && (
+            (sign == 0 && low <= i32::MAX as u64) || (sign != 0 && low <= 1u64 << 31));
+        if significant < n || fits_integer {
+            stats.canonical = false;
+        }
+        Ok(())
+    }
+
+    fn _skip_concrete_term(&mut self, tag: ErlTermTag, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        stats.tag_counts[tag as usize] += 1;
+        if tag == ErlTermTag::COMPRESSED {
+            // not a term by itself, just a wrapper
+            return self._skip_compressed(stats, depth);
+        }
+        stats.terms += 1;
+        if depth > stats.max_depth {
+            stats.max_depth = depth;
+        }
+        // containers have their own functions, so that each level of nesting
+        // takes little stack
+        match tag {
+            ErlTermTag::SMALL_TUPLE_EXT => {
+                let arity = self.rdr.read_u8()?;
+                self._skip_elems(stats, depth, arity as u32)
+            },
+            ErlTermTag::LARGE_TUPLE_EXT =>
+                self._skip_large_tuple(stats, depth),
+            ErlTermTag::LIST_EXT =>
+                self._skip_list(stats, depth),
+            ErlTermTag::MAP_EXT =>
+                self._skip_map(stats, depth),
+            ErlTermTag::FUN_EXT =>
+                self._skip_fun(stats, depth),
+            ErlTermTag::NEW_FUN_EXT =>
+                self._skip_new_fun(stats, depth),
+            _ =>
+                self._skip_scalar(tag, stats, depth),
+        }
+    }
+
+    fn _skip_elems(&mut self, stats: &mut TermStats, depth: usize, count: u32) -> Result<(), Error> {
+        for _ in 0..count {
+            self._skip_term(stats, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    fn _skip_large_tuple(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        let arity = self.rdr.read_u32::<BigEndian>()?;
+        if arity <= 255 {
+            stats.canonical = false;
+        }
+        self._skip_elems(stats, depth, arity)
+    }
+
+    fn _skip_list(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        let len = self.rdr.read_u32::<BigEndian>()?;
+        // OTP emits STRING_EXT for proper lists of 0..255 integers
+        let mut bytes_only = len > 0 && len <= u16::MAX as u32;
+        for _ in 0..len {
+            let elem = self._skip_term(stats, depth + 1)?;
+            bytes_only = bytes_only && elem == ErlTermTag::SMALL_INTEGER_EXT;
+        }
+        let tail = self._skip_term(stats, depth + 1)?;
+        if len == 0 || (bytes_only && tail == ErlTermTag::NIL_EXT) {
+            stats.canonical = false;
+        }
+        Ok(())
+    }
+
+    fn _skip_fun(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        let num_free = self.rdr.read_u32::<BigEndian>()?;
+        self._skip_some(stats, depth, &[ErlTermTag::PID_EXT, ErlTermTag::NEW_PID_EXT])?;
+        self._skip_any_atom(stats, depth)?;
+        self._skip_some(stats, depth, &[ErlTermTag::SMALL_INTEGER_EXT, ErlTermTag::INTEGER_EXT])?;
+        self._skip_some(stats, depth, &[ErlTermTag::SMALL_INTEGER_EXT, ErlTermTag::INTEGER_EXT])?;
+        self._skip_elems(stats, depth, num_free)
+    }
+
+    fn _skip_new_fun(&mut self, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        let _size = self.rdr.read_u32::<BigEndian>()?;
+        self._skip(1 + 16 + 4)?; // arity, uniq, index
+        let num_free = self.rdr.read_u32::<BigEndian>()?;
+        self._skip_any_atom(stats, depth)?;
+        self._skip_some(stats, depth, &[ErlTermTag::SMALL_INTEGER_EXT, ErlTermTag::INTEGER_EXT])?;
+        self._skip_some(stats, depth, &[ErlTermTag::SMALL_INTEGER_EXT, ErlTermTag::INTEGER_EXT])?;
+        self._skip_some(stats, depth, &[ErlTermTag::PID_EXT, ErlTermTag::NEW_PID_EXT])?;
+        self._skip_elems(stats, depth, num_free)
+    }
+
+    fn _skip_scalar(&mut self, tag: ErlTermTag, stats: &mut TermStats, depth: usize) -> Result<(), Error> {
+        match tag {
+            ErlTermTag::SMALL_INTEGER_EXT =>
+                self._skip(1),
+            ErlTermTag::INTEGER_EXT => {
+                let num = self.rdr.read_i32::<BigEndian>()?;
+                if (0..=255).contains(&num) {
+                    stats.canonical = false;
+                }
+                Ok(())
+            },
+            ErlTermTag::FLOAT_EXT => {
+                let mut buf = [0u8; 31];
+                self.rdr.read_exact(&mut buf)?;
+                let text = str::from_utf8(&buf)
+                    .map_err(|_| Error::UnexpectedTerm(ErlTermTag::FLOAT_EXT))?;
+                text.trim_end_matches('\0').parse::<f64>()?;
+                // OTP uses NEW_FLOAT_EXT since R11B
+                stats.canonical = false;
+                Ok(())
+            },
+            ErlTermTag::ATOM_EXT | ErlTermTag::ATOM_UTF8_EXT => {
+                let len = self.rdr.read_u16::<BigEndian>()?;
+                // OTP 26 writes utf8 atoms, SMALL_ATOM_UTF8_EXT when they fit
+                if tag == ErlTermTag::ATOM_EXT || len <= 255 {
+                    stats.canonical = false;
+                }
+                self._skip_atom_text(stats, len as usize, tag == ErlTermTag::ATOM_EXT)
+            },
+            ErlTermTag::SMALL_ATOM_EXT | ErlTermTag::SMALL_ATOM_UTF8_EXT => {
+                let len = self.rdr.read_u8()?;
+                if tag == ErlTermTag::SMALL_ATOM_EXT {
+                    stats.canonical = false;
+                }
+                self._skip_atom_text(stats, len as usize, tag == ErlTermTag::SMALL_ATOM_EXT)
+            },
+            ErlTermTag::REFERENCE_EXT => {
+                // legacy identifier tags are replaced since OTP 23
+                stats.canonical = false;
+                self._skip_any_atom(stats, depth)?;
+                self._skip(5)
+            },
+            ErlTermTag::PORT_EXT => {
+                stats.canonical = false;
+                self._skip_any_atom(stats, depth)?;
+                self._skip(5)
+            },
+            ErlTermTag::PID_EXT => {
+                stats.canonical = false;
+                self._skip_any_atom(stats, depth)?;
+                self._skip(9)
+            },
+            ErlTermTag::NEW_PORT_EXT => {
+                self._skip_any_atom(stats, depth)?;
+                self._skip(8)
+            },
+            ErlTermTag::V4_PORT_EXT => {
+                self._skip_any_atom(stats, depth)?;
+                // used only for ids, which don't fit into NEW_PORT_EXT
+                if self.rdr.read_u64::<BigEndian>()? <= u32::MAX as u64 {
+                    stats.canonical = false;
+                }
+                self._skip(4)
+            },
+            ErlTermTag::NEW_PID_EXT => {
+                self._skip_any_atom(stats, depth)?;
+                self._skip(12)
+            },
+            ErlTermTag::NIL_EXT =>
+                Ok(()),
+            ErlTermTag::STRING_EXT => {
+                let len = self.rdr.read_u16::<BigEndian>()?;
+                if len == 0 {
+                    // [] is NIL_EXT
+                    stats.canonical = false;
+                }
+                self._skip(len as u64)
+            },
+            ErlTermTag::BINARY_EXT => {
+                let len = self.rdr.read_u32::<BigEndian>()? as usize;
+                if len > stats.largest_binary {
+                    stats.largest_binary = len;
+                }
+                self._skip(len as u64)
+            },
+            ErlTermTag::SMALL_BIG_EXT => {
+                let n = self.rdr.read_u8()?;
+                self._skip_big(stats, n as usize)
+            },
+            ErlTermTag::LARGE_BIG_EXT => {
+                let n = self.rdr.read_u32::<BigEndian>()?;
+                if n <= 255 {
+                    stats.canonical = false;
+                }
+                self._skip_big(stats, n as usize)
+            },
+            ErlTermTag::NEW_REFERENCE_EXT => {
+                stats.canonical = false;
+                let len = self.rdr.read_u16::<BigEndian>()?;
+                self._skip_any_atom(stats, depth)?;
+                self._skip(1 + 4 * len as u64)
+            },
+            ErlTermTag::NEWER_REFERENCE_EXT => {
+                let len = self.rdr.read_u16::<BigEndian>()?;
+                self._skip_any_atom(stats, depth)?;
+                self._skip(4 + 4 * len as u64)
+            },
+            ErlTermTag::EXPORT_EXT => {
+                self._skip_any_atom(stats, depth)?;
+                self._skip_any_atom(stats, depth)?;
+                self._skip_some(stats, depth, &[ErlTermTag::SMALL_INTEGER_EXT]).map(|_| ())
+            },
+            ErlTermTag::BIT_BINARY_EXT => {
+                let (len, bits) = self._read_bit_binary_header()?;
+                let len = len as usize;
+                if bits == 8 {
+                    // whole bytes, should be BINARY_EXT
+                    stats.canonical = false;
+                }
+                if len > stats.largest_binary {
+                    stats.largest_binary = len;
+                }
+                self._skip(len as u64)
+            },
+            ErlTermTag::NEW_FLOAT_EXT =>
+                self._skip(8),
+            ErlTermTag::SMALL_TUPLE_EXT | ErlTermTag::LARGE_TUPLE_EXT | ErlTermTag::LIST_EXT |
+            ErlTermTag::MAP_EXT | ErlTermTag::FUN_EXT | ErlTermTag::NEW_FUN_EXT |
+            ErlTermTag::COMPRESSED =>
+                unreachable!(),
+        }
+    }
+}
+
+
+// Copies everything read from inner reader
+struct Recorder<'r> {
+    inner: &'r mut dyn io::Read,    // not generic, so decoder type doesn't nest
+    bytes: Vec<u8>,
+}
+
+impl io::Read for Recorder<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+
+// Checks the whole term without writing anything
+pub fn check_term(term: &Eterm, options: &EncoderOptions) -> Result<(), Error> {
+    match *term {
+        Eterm::SmallInteger(_) | Eterm::Integer(_) | Eterm::Nil =>
+            Ok(()),
+        Eterm::BigNum(ref num) =>
+            check_length((num.bits() as usize).div_ceil(8)),
+        Eterm::Float(num) =>
+            check_float(num),
+        Eterm::Atom(ref atom) =>
+            check_atom(atom),
+        Eterm::Reference(ref reference) => {
+            check_atom(&reference.node)?;
+            check_creation(reference.creation, options)?;
+            check_reference_id(&reference.id)
+        },
+        Eterm::Port(ref port) => {
+            check_atom(&port.node)?;
+            check_creation(port.creation, options)?;
+            check_port_id(port.id, options)
+        },
+        Eterm::Pid(ref pid) => {
+            check_atom(&pid.node)?;
+            check_creation(pid.creation, options)
+        },
+        Eterm::Tuple(ref elems) => {
+            check_length(elems.len())?;
+            elems.iter().try_for_each(|elem| check_term(elem, options))
+        },
+        Eterm::List(ref elems) => {
+            check_list(elems)?;
+            elems.iter().try_for_each(|elem| check_term(elem, options))
+        },
+        Eterm::Map(ref pairs) => {
+            check_length(pairs.len())?;
+            pairs.iter().try_for_each(|(key, val)| {
+                check_term(key, options)?;
+                check_term(val, options)
+            })
+        },
+        Eterm::String(ref s) =>
+            check_length(s.len()),
+        Eterm::Binary(ref bin) =>
+            check_length(bin.len()),
+        Eterm::BitBinary(ref bit_binary) =>
+            check_bit_binary(bit_binary.bits, &bit_binary.data),
+        Eterm::Fun(ref fun) => {
+            check_atom(&fun.module)?;
+            check_creation(fun.pid.creation, options)?;
+            check_atom(&fun.pid.node)?;
+            check_length(fun.free_vars.len())?;
+            fun.free_vars.iter().try_for_each(|elem| check_term(elem, options))
+        },
+        Eterm::NewFun(ref fun) => {
+            check_fun_uniq(&fun.uniq)?;
+            check_atom(&fun.module)?;
+            check_creation(fun.pid.creation, options)?;
+            check_atom(&fun.pid.node)?;
+            check_length(fun.free_vars.len())?;
+            fun.free_vars.iter().try_for_each(|elem| check_term(elem, options))
+        },
+        Eterm::Export(ref export) => {
+            check_atom(&export.module)?;
+            check_atom(&export.function)
+        },
+    }
+}
+
+pub(crate) fn check_atom(atom: &str) -> Result<(), Error> {
+    // limit is in characters, so utf8 text always fits ATOM_EXT's u16
+    let chars = atom.chars().count();
+    if chars > 255 {
+        return Err(Error::AtomTooLong(chars));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_reference_id(id: &[u8]) -> Result<(), Error> {
+    if id.is_empty() || id.len() % 4 != 0 || id.len() / 4 > u16::MAX as usize {
+        return Err(Error::BadReferenceId(id.len()));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_fun_uniq(uniq: &[u8]) -> Result<(), Error> {
+    if uniq.len() != 16 {
+        return Err(Error::BadFunUniq(uniq.len()));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_float(num: f64) -> Result<(), Error> {
+    if !num.is_finite() {
+        return Err(Error::NonFiniteFloat(num));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_creation(creation: u32, options: &EncoderOptions) -> Result<(), Error> {
+    if options.identifier_tags == IdentifierTags::Legacy && creation > u8::MAX as u32 {
+        return Err(Error::CreationTooLarge(creation));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_port_id(id: u64, options: &EncoderOptions) -> Result<(), Error> {
+    if options.identifier_tags != IdentifierTags::V4 && id > u32::MAX as u64 {
+        return Err(Error::PortIdTooLarge(id));
+    }
+    Ok(())
+}
+
+pub(crate) fn check_bit_binary(bits: u8, data: &[u8]) -> Result<(), Error> {
+    if bits == 0 || bits > 8 || data.is_empty() {
+        return Err(Error::BadBitBinary(bits, data.len()));
+    }
+    check_length(data.len())
+}
+
+pub(crate) fn check_list(elems: &[Eterm]) -> Result<(), Error> {
+    if elems.is_empty() {
+        return Err(Error::EmptyList);
+    }
+    check_length(elems.len() - 1)
+}
+
+pub(crate) fn check_length(len: usize) -> Result<(), Error> {
+    if len > u32::MAX as usize {
+        return Err(Error::LengthTooLarge(len));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::super::{Atom, AtomTable, Decoder, DecoderOptions, Encoder, EncoderOptions, IdentifierTags, Eterm, ErlTermTag, Error};
+    use super::{TermStats, check_term};
+    use std::io;
+
+    fn validate(bytes: Vec<u8>) -> Result<TermStats, Error> {
+        let mut rdr = io::Cursor::new(bytes);
+        let mut decoder = Decoder::new(&mut rdr);
+        assert!(decoder.read_prelude().unwrap());
+        decoder.validate_term()
+    }
+
+    fn encode(term: Eterm) -> Vec<u8> {
+        let mut buf = Vec::new();
+        {
+            let mut encoder = Encoder::new(&mut buf, EncoderOptions::otp(26));
+            encoder.write_prelude().unwrap();
+            encoder.encode_term(term).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn validate_stats() {
+        let term = Eterm::Tuple(vec!(
+            Eterm::Atom(Atom::from("ok")),
+            Eterm::List(vec!(Eterm::Binary(vec!(0; 10)),
+                             Eterm::Binary(vec!(0; 3)),
+                             Eterm::Nil)),
+            Eterm::Atom(Atom::from("done"))));
+        let stats = validate(encode(term)).unwrap();
+        assert_eq!(7, stats.terms);
+        assert_eq!(3, stats.max_depth);
+        assert_eq!(10, stats.largest_binary);
+        assert_eq!(6, stats.atom_bytes);
+        assert_eq!(2, stats.tag_count(ErlTermTag::SMALL_ATOM_UTF8_EXT));
+        assert_eq!(2, stats.tag_count(ErlTermTag::BINARY_EXT));
+        assert_eq!(1, stats.tag_count(ErlTermTag::SMALL_TUPLE_EXT));
+        assert!(stats.canonical);
+    }
+
+    #[test]
+    fn validate_non_canonical() {
+        // 5 as INTEGER_EXT
+        assert!(!validate(vec!(131, 98, 0, 0, 0, 5)).unwrap().canonical);
+        // 5 as SMALL_BIG_EXT
+        assert!(!validate(vec!(131, 110, 1, 0, 5)).unwrap().canonical);
+        // [1] as LIST_EXT
+        assert!(!validate(vec!(131, 108, 0, 0, 0, 1, 97, 1, 106)).unwrap().canonical);
+        // [] as STRING_EXT
+        assert!(!validate(vec!(131, 107, 0, 0)).unwrap().canonical);
+        // 'a' as ATOM_EXT, ATOM_UTF8_EXT and SMALL_ATOM_EXT
+        assert!(!validate(vec!(131, 100, 0, 1, 97)).unwrap().canonical);
+        assert!(!validate(vec!(131, 118, 0, 1, 97)).unwrap().canonical);
+        assert!(!validate(vec!(131, 115, 1, 97)).unwrap().canonical);
+        // 2^32 as SMALL_BIG_EXT is fine
+        assert!(validate(vec!(131, 110, 5, 0, 0, 0, 0, 0, 1)).unwrap().canonical);
+        assert!(validate(vec!(131, 119, 1, 97)).unwrap().canonical);
+    }
+
+    #[test]
+    fn validate_broken() {
+        // truncated binary
+        assert!(validate(vec!(131, 109, 0, 0, 0, 5, 1, 2)).is_err());
+        // unknown tag inside tuple
+        match validate(vec!(131, 104, 1, 1)) {
+            Err(Error::UnknownTag(1)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    // Validator and decoder agree on bad input
+    fn both_reject(bytes: Vec<u8>, options: DecoderOptions) -> Error {
+        let mut rdr = io::Cursor::new(&bytes[1..]);
+        let decoded = Decoder::with_options(&mut rdr, options.clone()).decode_term();
+        let mut rdr = io::Cursor::new(&bytes[1..]);
+        let validated = Decoder::with_options(&mut rdr, options).validate_term();
+        match (decoded, validated) {
+            (Err(err), Err(other)) => {
+                assert_eq!(err.to_string(), other.to_string());
+                err
+            },
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn validate_like_decoder() {
+        // {'\xff'} as SMALL_ATOM_UTF8_EXT and ATOM_UTF8_EXT
+        both_reject(vec!(131, 104, 1, 119, 1, 255), DecoderOptions::new());
+        both_reject(vec!(131, 104, 1, 118, 0, 1, 255), DecoderOptions::new());
+        // but latin1 atom is fine
+        assert!(validate(vec!(131, 104, 1, 115, 1, 255)).is_ok());
+        // #{1 => a, 1 => b}, second key as INTEGER_EXT
+        match both_reject(vec!(131, 116, 0, 0, 0, 2, 97, 1, 119, 1, 97, 98, 0, 0, 0, 1, 119, 1, 98),
+                          DecoderOptions::new()) {
+            Error::DuplicateKey(Eterm::Integer(1)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        // [[[]]]
+        let nested = vec!(131, 108, 0, 0, 0, 1, 108, 0, 0, 0, 1, 106, 106, 106);
+        match both_reject(nested.clone(), DecoderOptions::new().max_depth(2)) {
+            Error::TooDeep(2) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        assert_eq!(3, validate(nested).unwrap().max_depth);
+        // <<>> as BIT_BINARY_EXT, 0 and 9 bits in the last byte
+        both_reject(vec!(131, 77, 0, 0, 0, 0, 8), DecoderOptions::new());
+        both_reject(vec!(131, 77, 0, 0, 0, 1, 0, 5), DecoderOptions::new());
+        both_reject(vec!(131, 77, 0, 0, 0, 1, 9, 5), DecoderOptions::new());
+    }
+
+    #[test]
+    fn validate_node_depth() {
+        // node atom of a pid is a part of the pid, not a nested term
+        let bytes = vec!(131, 88, 119, 1, 97, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0);
+        let options = DecoderOptions::new().max_depth(1);
+        let mut rdr = io::Cursor::new(&bytes[1..]);
+        assert!(Decoder::with_options(&mut rdr, options.clone()).decode_term().is_ok());
+        let mut rdr = io::Cursor::new(&bytes[1..]);
+        let stats = Decoder::with_options(&mut rdr, options).validate_term().unwrap();
+        assert_eq!(1, stats.max_depth);
+        assert_eq!(2, stats.terms);
+    }
+
+    #[test]
+    fn validate_keys_without_atom_table() {
+        // #{a => 1}
+        let bytes = vec!(131, 116, 0, 0, 0, 1, 119, 1, 97, 97, 1);
+        let table = AtomTable::with_limit(0);
+        let mut rdr = io::Cursor::new(&bytes[1..]);
+        let options = DecoderOptions::new().atom_table(Some(table.clone()));
+        assert!(Decoder::with_options(&mut rdr, options).validate_term().is_ok());
+        assert_eq!(0, table.len());
+    }
+
+    #[test]
+    fn validate_max_depth() {
+        // {{{...}}} of default max depth decodes fine, one more level doesn't
+        let mut bytes = vec!(131);
+        for _ in 0..511 {
+            bytes.extend(&[104, 1]);
+        }
+        bytes.push(106);
+        assert_eq!(512, validate(bytes.clone()).unwrap().max_depth);
+        let mut rdr = io::Cursor::new(&bytes[1..]);
+        assert!(Decoder::new(&mut rdr).decode_term().is_ok());
+        bytes.insert(1, 104);
+        bytes.insert(2, 1);
+        match both_reject(bytes, DecoderOptions::new()) {
+            Error::TooDeep(512) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn check_options() {
+        let port = Eterm::Port(super::super::Port {
+            node: Atom::from("my_node"),
+            id: 1 << 32,
+            creation: 1 << 16
+        });
+        let term = Eterm::Tuple(vec!(Eterm::Atom(Atom::from("ok")), port));
+        match check_term(&term, &EncoderOptions::new()) {
+            Err(Error::CreationTooLarge(65536)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        let options = EncoderOptions::new().identifier_tags(IdentifierTags::BigCreation);
+        match check_term(&term, &options) {
+            Err(Error::PortIdTooLarge(4294967296)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+        let options = options.identifier_tags(IdentifierTags::V4);
+        assert!(check_term(&term, &options).is_ok());
+        let bad = Eterm::BitBinary(super::super::BitBinary{bits: 0, data: vec!(1)});
+        match check_term(&Eterm::List(vec!(bad, Eterm::Nil)), &options) {
+            Err(Error::BadBitBinary(0, 1)) => (),
+            other => panic!("Unexpected {:?}", other),
+        }
+    }
+}
@@ -1,6 +1,6 @@
 extern crate erl_ext;
 
-use erl_ext::{Encoder,Decoder};
+use erl_ext::{Encoder,EncoderOptions,Decoder};
 use std::io;
 use std::io::{Write,Read};
 use std::fs;
@@ -22,10 +22,10 @@ fn main() {
     };
     // run decode-encode cycle and compare source and resulting binaries
     let data_dir = Path::new("tests/data");
-    for path in (fs::read_dir(&data_dir)
+    for path in fs::read_dir(data_dir)
                  .unwrap()
                  .map(|de| de.unwrap().path())
-                 .filter(|ref p| p.extension().unwrap().to_str() == Some("bin"))) {
+                 .filter(|p| p.extension().unwrap().to_str() == Some("bin")) {
 
         let mut in_f = fs::File::open(&path).unwrap();
         let mut src = Vec::new();
@@ -37,11 +37,11 @@ fn main() {
 
         {
             let mut decoder = Decoder::new(&mut rdr);
-            assert!(true == decoder.read_prelude().unwrap(),
+            assert!(decoder.read_prelude().unwrap(),
                     "{}: bad prelude", path.display());
             let term = decoder.decode_term().unwrap();
 
-            let mut encoder = Encoder::new(&mut wrtr, false, false, true);
+            let mut encoder = Encoder::new(&mut wrtr, EncoderOptions::new());
             encoder.write_prelude().unwrap();
             encoder.encode_term(term).unwrap();
             encoder.flush().unwrap();
@@ -0,0 +1,34 @@
+extern crate erl_ext;
+extern crate num;
+
+use erl_ext::{Atom, Eterm, EncoderOptions, AtomEncoding};
+use erl_ext::port::{Packet, PortClient};
+use std::env;
+use std::process;
+use num::bigint;
+
+// Rust version of examples/erlang_rust_port.erl
+#[test]
+fn main() {
+    // examples are built by `cargo test` next to the `deps` dir of this test
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    path.pop();
+    path.push("examples");
+    path.push("erlang_rust_port");
+    let mut command = process::Command::new(&path);
+    command.args(["-u", "-s", "-f"]).env("RUST_BACKTRACE", "1");
+    let options = EncoderOptions::new().atom_encoding(AtomEncoding::Utf8).small_atoms(true);
+    let mut port = PortClient::spawn(&mut command, Packet::Stream, options)
+        .unwrap_or_else(|err| panic!("Failed to launch {} - '{}'", path.display(), err));
+    let term = Eterm::Map(vec!(
+        (Eterm::String(b"string".to_vec()), Eterm::Atom(Atom::from("atom"))),
+        (Eterm::Float(2.5), Eterm::Tuple(vec!())),
+        (Eterm::Nil, Eterm::BigNum(bigint::BigInt::parse_bytes(
+            b"-100000000000000000000000000", 10).unwrap())),
+        (Eterm::List(vec!(Eterm::Integer(1000), Eterm::Nil)), Eterm::Binary(vec!(0; 128)))).into());
+    for _ in 0..500 {
+        assert_eq!(term, port.call(&term).unwrap());
+    }
+    assert!(port.close().unwrap().success());
+}